@@ -0,0 +1,78 @@
+use acid4sigmas_models::error_response;
+use actix_web::{delete, get, web, HttpRequest, HttpResponse, Responder};
+use serde_json::json;
+
+use crate::auth;
+use crate::cache::CACHE_MANAGER;
+use crate::error::{ApiError, ErrorCode};
+use crate::policy;
+use crate::rate_limit::RATE_LIMITER;
+use crate::revocation;
+
+/// Rejects the request unless it carries a valid token with a role the policy
+/// marks as `admin`.
+async fn authorize(req: &HttpRequest) -> Result<(), HttpResponse> {
+    let token = auth::bearer_token(req).ok_or_else(|| error_response!(401, "no token found."))?;
+
+    let claims = auth::verify_backend_token(token).map_err(|e| e.to_http_response())?;
+
+    revocation::check(&claims)
+        .await
+        .map_err(|e| e.to_http_response())?;
+
+    if !policy::get().is_admin(&claims) {
+        return Err(
+            ApiError::new(ErrorCode::Forbidden, "admin endpoints need an admin role.")
+                .to_http_response(),
+        );
+    }
+
+    Ok(())
+}
+
+#[get("/admin/cache")]
+async fn cache_stats(req: HttpRequest) -> impl Responder {
+    if let Err(res) = authorize(&req).await {
+        return res;
+    }
+
+    HttpResponse::Ok().json(CACHE_MANAGER.stats())
+}
+
+#[delete("/admin/cache")]
+async fn flush_cache(req: HttpRequest) -> impl Responder {
+    if let Err(res) = authorize(&req).await {
+        return res;
+    }
+
+    CACHE_MANAGER.flush().await;
+
+    HttpResponse::Ok().json(json!({ "status": "Cache flushed." }))
+}
+
+#[delete("/admin/cache/{table}")]
+async fn flush_table_cache(req: HttpRequest, table: web::Path<String>) -> impl Responder {
+    if let Err(res) = authorize(&req).await {
+        return res;
+    }
+
+    CACHE_MANAGER.invalidate_table(&table).await;
+
+    HttpResponse::Ok().json(json!({ "status": format!("Cache of {} flushed.", table) }))
+}
+
+#[get("/admin/rate_limits")]
+async fn rate_limit_stats(req: HttpRequest) -> impl Responder {
+    if let Err(res) = authorize(&req).await {
+        return res;
+    }
+
+    HttpResponse::Ok().json(RATE_LIMITER.stats())
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(cache_stats)
+        .service(flush_cache)
+        .service(flush_table_cache)
+        .service(rate_limit_stats);
+}
@@ -0,0 +1,76 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use actix_web::http::header::AUTHORIZATION;
+use actix_web::HttpRequest;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+use crate::error::ApiError;
+use crate::keyset;
+
+/// Returns the token of an `Authorization: Bearer <token>` header.
+pub fn bearer_token(req: &HttpRequest) -> Option<&str> {
+    req.headers()
+        .get(AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+        .map(str::trim)
+}
+
+/// Claims of a verified token that the api acts on.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct TokenClaims {
+    /// expiry, in seconds since the unix epoch
+    pub exp: Option<u64>,
+    /// subject the token was issued to
+    pub sub: Option<Value>,
+    /// id of the token, its row in `auth_tokens`
+    pub jti: Option<String>,
+    pub role: Option<String>,
+    #[serde(default)]
+    pub roles: Vec<String>,
+    /// space separated scopes
+    pub scope: Option<String>,
+    /// every other claim, for the row rules of the policy
+    #[serde(flatten)]
+    pub other: Map<String, Value>,
+}
+
+impl TokenClaims {
+    /// Time left until the token expires, `None` if it never does.
+    pub fn expires_in(&self) -> Option<Duration> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        self.exp
+            .map(|exp| Duration::from_secs(exp.saturating_sub(now)))
+    }
+
+    /// Every role and scope of the token, the policy treats them alike.
+    pub fn roles(&self) -> Vec<&str> {
+        let scopes = self.scope.iter().flat_map(|scope| scope.split_whitespace());
+
+        self.role
+            .iter()
+            .chain(&self.roles)
+            .map(String::as_str)
+            .chain(scopes)
+            .collect()
+    }
+
+    /// Returns the claim called `name`.
+    pub fn claim(&self, name: &str) -> Option<&Value> {
+        match name {
+            "sub" => self.sub.as_ref(),
+            name => self.other.get(name),
+        }
+    }
+}
+
+/// Checks that `token` is a valid backend token and returns its claims.
+pub fn verify_backend_token(token: &str) -> Result<TokenClaims, ApiError> {
+    keyset::get().verify(token)
+}
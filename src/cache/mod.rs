@@ -0,0 +1,737 @@
+use lru_cache::LruCache;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::config::{self, CacheBackendKind};
+
+pub mod redis_cache;
+pub mod single_flight;
+
+use redis_cache::RedisCache;
+use single_flight::SingleFlight;
+
+pub type CachedRows = Vec<serde_json::Value>;
+
+lazy_static::lazy_static! {
+    /// the backend selected in the config, initialize it inside the runtime
+    pub static ref CACHE_MANAGER: Box<dyn CacheBackend> = build_backend();
+    /// retrieves that missed the cache and are currently querying the database,
+    /// by the generation of their table they started at
+    pub static ref RETRIEVE_FLIGHTS: SingleFlight<(CacheKey, u64), CachedRows> = SingleFlight::new();
+}
+
+fn build_backend() -> Box<dyn CacheBackend> {
+    let cache_config = &config::get().cache;
+
+    match cache_config.backend {
+        CacheBackendKind::Memory => Box::new(CacheManager::<CacheKey, CachedRows>::new(
+            cache_config.max_bytes,
+        )),
+        CacheBackendKind::Redis => match RedisCache::new(cache_config) {
+            Ok(cache) => Box::new(cache),
+            Err(e) => {
+                eprintln!(
+                    "error: failed to set up redis cache, falling back to memory: {}",
+                    e
+                );
+                Box::new(CacheManager::<CacheKey, CachedRows>::new(
+                    cache_config.max_bytes,
+                ))
+            }
+        },
+    }
+}
+
+/// Operations every cache backend provides to the database actions.
+#[async_trait::async_trait]
+pub trait CacheBackend: Send + Sync {
+    fn get(&self, key: &CacheKey) -> Option<CachedRows>;
+    /// Caches `value` unless `key.table` was invalidated since `generation`,
+    /// the generation the value was read at.
+    fn insert(&self, key: CacheKey, value: CachedRows, ttl: Option<Duration>, generation: u64);
+    /// Changes whenever `table_name` is invalidated or the cache flushed, on
+    /// this instance.
+    fn generation(&self, table_name: &str) -> u64;
+    /// Removes every cached entry of `table_name`, on every instance sharing the backend.
+    async fn invalidate_table(&self, table_name: &str);
+    /// Removes every cached entry, on every instance sharing the backend.
+    async fn flush(&self);
+    fn stats(&self) -> CacheStats;
+}
+
+#[async_trait::async_trait]
+impl CacheBackend for CacheManager<CacheKey, CachedRows> {
+    fn get(&self, key: &CacheKey) -> Option<CachedRows> {
+        CacheManager::get(self, &key.table, key)
+    }
+
+    fn insert(&self, key: CacheKey, value: CachedRows, ttl: Option<Duration>, generation: u64) {
+        let table_name = key.table.clone();
+        CacheManager::insert(self, &table_name, key, value, ttl, generation)
+    }
+
+    fn generation(&self, table_name: &str) -> u64 {
+        CacheManager::generation(self, table_name)
+    }
+
+    async fn invalidate_table(&self, table_name: &str) {
+        CacheManager::invalidate_table(self, table_name)
+    }
+
+    async fn flush(&self) {
+        CacheManager::flush(self)
+    }
+
+    fn stats(&self) -> CacheStats {
+        CacheManager::stats(self)
+    }
+}
+
+/// How a single request wants to use the cache.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CacheMode {
+    /// read from and write to the cache
+    #[default]
+    Default,
+    /// skip the cache lookup but store the fresh result
+    Refresh,
+    /// neither read from nor write to the cache
+    NoCache,
+}
+
+impl CacheMode {
+    pub fn reads(self) -> bool {
+        self == CacheMode::Default
+    }
+
+    pub fn writes(self) -> bool {
+        self != CacheMode::NoCache
+    }
+}
+
+/// Approximate in-memory size of a cached value, used for the byte budget.
+pub trait CacheWeight {
+    fn weight(&self) -> usize;
+}
+
+impl CacheWeight for serde_json::Value {
+    fn weight(&self) -> usize {
+        use serde_json::Value;
+
+        let own = std::mem::size_of::<Value>();
+
+        match self {
+            Value::Null | Value::Bool(_) | Value::Number(_) => own,
+            Value::String(s) => own + s.capacity(),
+            Value::Array(values) => own + values.iter().map(|v| v.weight()).sum::<usize>(),
+            Value::Object(map) => {
+                own + map
+                    .iter()
+                    .map(|(k, v)| k.capacity() + v.weight())
+                    .sum::<usize>()
+            }
+        }
+    }
+}
+
+impl<T: CacheWeight> CacheWeight for Vec<T> {
+    fn weight(&self) -> usize {
+        std::mem::size_of::<Self>() + self.iter().map(|v| v.weight()).sum::<usize>()
+    }
+}
+
+/// Cache counters, kept for the whole cache and for every table.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct CacheCounters {
+    pub hits: u64,
+    pub misses: u64,
+    pub inserts: u64,
+    pub evictions: u64,
+    pub invalidations: u64,
+    pub entries: usize,
+    pub bytes: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CacheStats {
+    #[serde(flatten)]
+    pub total: CacheCounters,
+    pub max_bytes: usize,
+    /// whether the instances share their invalidations, always true for the
+    /// memory backend
+    pub healthy: bool,
+    pub tables: HashMap<String, CacheCounters>,
+}
+
+/// LRU cache whose keys are additionally indexed by the table they belong to,
+/// so a whole table can be invalidated without scanning every key.
+///
+/// The cache is bounded by the approximate size of its values rather than by
+/// entry count, and entries can carry a time to live. The table index only
+/// ever holds keys that are still in the LRU.
+pub struct CacheManager<K, V>
+where
+    K: Eq + Hash,
+{
+    max_bytes: usize,
+    state: Mutex<CacheState<K, V>>,
+}
+
+struct CacheState<K, V>
+where
+    K: Eq + Hash,
+{
+    entries: LruCache<K, CacheEntry<V>>,
+    tables: HashMap<String, HashSet<K>>,
+    total: CacheCounters,
+    counters: HashMap<String, CacheCounters>,
+    /// generation each table was last invalidated at
+    generations: HashMap<String, u64>,
+    /// generation the whole cache was last flushed at
+    flushed: u64,
+    latest: u64,
+}
+
+struct CacheEntry<V> {
+    table: String,
+    value: V,
+    weight: usize,
+    expires_at: Option<Instant>,
+}
+
+impl<V> CacheEntry<V> {
+    fn is_expired(&self, now: Instant) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+impl<K, V> CacheState<K, V>
+where
+    K: Eq + Hash + Clone,
+{
+    fn counters(&mut self, table_name: &str) -> &mut CacheCounters {
+        if !self.counters.contains_key(table_name) {
+            self.counters
+                .insert(table_name.to_string(), CacheCounters::default());
+        }
+
+        self.counters.get_mut(table_name).unwrap()
+    }
+
+    fn unindex(&mut self, key: &K, entry: &CacheEntry<V>) {
+        self.total.entries -= 1;
+        self.total.bytes -= entry.weight;
+
+        let counters = self.counters(&entry.table);
+        counters.entries -= 1;
+        counters.bytes -= entry.weight;
+
+        if let Some(keys) = self.tables.get_mut(&entry.table) {
+            keys.remove(key);
+
+            if keys.is_empty() {
+                self.tables.remove(&entry.table);
+            }
+        }
+    }
+
+    fn remove(&mut self, key: &K) -> Option<CacheEntry<V>> {
+        let entry = self.entries.remove(key)?;
+        self.unindex(key, &entry);
+        Some(entry)
+    }
+
+    fn evict(&mut self, key: &K) {
+        if let Some(entry) = self.remove(key) {
+            self.total.evictions += 1;
+            self.counters(&entry.table).evictions += 1;
+        }
+    }
+
+    fn generation(&self, table_name: &str) -> u64 {
+        let invalidated = self.generations.get(table_name).copied().unwrap_or(0);
+        invalidated.max(self.flushed)
+    }
+
+    fn invalidate(&mut self, table_name: &str) {
+        let Some(keys) = self.tables.remove(table_name) else {
+            return;
+        };
+
+        let mut removed = 0;
+        let mut removed_bytes = 0;
+
+        for key in keys {
+            if let Some(entry) = self.entries.remove(&key) {
+                removed += 1;
+                removed_bytes += entry.weight;
+            }
+        }
+
+        self.total.entries -= removed;
+        self.total.bytes -= removed_bytes;
+        self.total.invalidations += removed as u64;
+
+        let counters = self.counters(table_name);
+        counters.entries -= removed;
+        counters.bytes -= removed_bytes;
+        counters.invalidations += removed as u64;
+    }
+}
+
+impl<K, V> CacheManager<K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Clone + CacheWeight,
+{
+    pub fn new(max_bytes: usize) -> Self {
+        let state = CacheState {
+            // the byte budget is what bounds the cache, not the entry count
+            entries: LruCache::new(usize::MAX),
+            tables: HashMap::new(),
+            total: CacheCounters::default(),
+            counters: HashMap::new(),
+            generations: HashMap::new(),
+            flushed: 0,
+            latest: 0,
+        };
+
+        CacheManager {
+            max_bytes,
+            state: Mutex::new(state),
+        }
+    }
+
+    pub fn get(&self, table_name: &str, key: &K) -> Option<V> {
+        let mut state = self.state.lock().unwrap();
+
+        let expired = state
+            .entries
+            .get_mut(key)
+            .map(|entry| entry.is_expired(Instant::now()));
+
+        if expired == Some(true) {
+            state.evict(key);
+        }
+
+        let value = state.entries.get_mut(key).map(|entry| entry.value.clone());
+
+        if value.is_some() {
+            state.total.hits += 1;
+            state.counters(table_name).hits += 1;
+        } else {
+            state.total.misses += 1;
+            state.counters(table_name).misses += 1;
+        }
+
+        value
+    }
+
+    /// Caches `value` under `key` for `table_name`. `ttl` of `None` keeps the
+    /// entry until it is evicted or invalidated.
+    ///
+    /// `generation` is the one of `table_name` before `value` was read. If the
+    /// table was invalidated since, `value` may predate the write that did it
+    /// and is not cached.
+    pub fn insert(
+        &self,
+        table_name: &str,
+        key: K,
+        value: V,
+        ttl: Option<Duration>,
+        generation: u64,
+    ) {
+        let weight = value.weight();
+
+        if weight > self.max_bytes {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+
+        if state.generation(table_name) != generation {
+            return;
+        }
+
+        state.remove(&key);
+
+        while state.total.bytes + weight > self.max_bytes {
+            // evict by hand so the evicted key can be dropped from the table index too
+            match state.entries.remove_lru() {
+                Some((evicted_key, evicted)) => {
+                    state.unindex(&evicted_key, &evicted);
+                    state.total.evictions += 1;
+                    state.counters(&evicted.table).evictions += 1;
+                }
+                None => break,
+            }
+        }
+
+        let entry = CacheEntry {
+            table: table_name.to_string(),
+            value,
+            weight,
+            expires_at: ttl.map(|ttl| Instant::now() + ttl),
+        };
+
+        state.total.inserts += 1;
+        state.total.entries += 1;
+        state.total.bytes += weight;
+
+        let counters = state.counters(table_name);
+        counters.inserts += 1;
+        counters.entries += 1;
+        counters.bytes += weight;
+
+        state.entries.insert(key.clone(), entry);
+        state
+            .tables
+            .entry(table_name.to_string())
+            .or_default()
+            .insert(key);
+    }
+
+    /// The current generation of `table_name`, see `insert`.
+    pub fn generation(&self, table_name: &str) -> u64 {
+        self.state.lock().unwrap().generation(table_name)
+    }
+
+    /// Removes every cached entry that belongs to `table_name`.
+    pub fn invalidate_table(&self, table_name: &str) {
+        let mut state = self.state.lock().unwrap();
+
+        state.latest += 1;
+        let latest = state.latest;
+        state.generations.insert(table_name.to_string(), latest);
+
+        state.invalidate(table_name);
+    }
+
+    /// Removes every cached entry of every table.
+    pub fn flush(&self) {
+        let mut state = self.state.lock().unwrap();
+
+        state.latest += 1;
+        state.flushed = state.latest;
+
+        let tables: Vec<String> = state.tables.keys().cloned().collect();
+
+        for table_name in tables {
+            state.invalidate(&table_name);
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let state = self.state.lock().unwrap();
+
+        CacheStats {
+            total: state.total.clone(),
+            max_bytes: self.max_bytes,
+            healthy: true,
+            tables: state.counters.clone(),
+        }
+    }
+}
+
+/// Identifies a cached query result.
+///
+/// The table is kept as its own field, so table-level invalidation never has
+/// to derive it from the digest. The digest is a blake3 hash over a canonical,
+/// type-tagged encoding of the query and its parameters, which stays the same
+/// across Rust releases and processes.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    pub table: String,
+    pub digest: [u8; 32],
+}
+
+impl CacheKey {
+    pub fn generate_cache_key(
+        table_name: &str,
+        query: &str,
+        params: &[serde_json::Value],
+    ) -> CacheKey {
+        let mut hasher = blake3::Hasher::new();
+
+        Self::hash_str(&mut hasher, query);
+
+        hasher.update(&(params.len() as u64).to_le_bytes());
+        for param in params {
+            Self::hash_value(&mut hasher, param);
+        }
+
+        CacheKey {
+            table: table_name.to_string(),
+            digest: *hasher.finalize().as_bytes(),
+        }
+    }
+
+    fn hash_str(hasher: &mut blake3::Hasher, s: &str) {
+        // length prefixed so neighbouring strings can't run into each other
+        hasher.update(&(s.len() as u64).to_le_bytes());
+        hasher.update(s.as_bytes());
+    }
+
+    fn hash_value(hasher: &mut blake3::Hasher, value: &serde_json::Value) {
+        use serde_json::Value;
+
+        match value {
+            Value::Null => {
+                hasher.update(b"n");
+            }
+            Value::Bool(b) => {
+                hasher.update(if *b { b"t" } else { b"f" });
+            }
+            Value::Number(num) => {
+                // `1` and `1.0` bind as different sql types, so they must not share a key
+                if let Some(int_value) = num.as_i64() {
+                    hasher.update(b"i");
+                    hasher.update(&int_value.to_le_bytes());
+                } else if let Some(uint_value) = num.as_u64() {
+                    hasher.update(b"u");
+                    hasher.update(&uint_value.to_le_bytes());
+                } else {
+                    hasher.update(b"d");
+                    hasher.update(&num.as_f64().unwrap_or(f64::NAN).to_bits().to_le_bytes());
+                }
+            }
+            Value::String(s) => {
+                hasher.update(b"s");
+                Self::hash_str(hasher, s);
+            }
+            Value::Array(values) => {
+                hasher.update(b"a");
+                hasher.update(&(values.len() as u64).to_le_bytes());
+                for value in values {
+                    Self::hash_value(hasher, value);
+                }
+            }
+            Value::Object(map) => {
+                hasher.update(b"o");
+                hasher.update(&(map.len() as u64).to_le_bytes());
+
+                // key order must not matter
+                let mut entries: Vec<_> = map.iter().collect();
+                entries.sort_by(|a, b| a.0.cmp(b.0));
+
+                for (key, value) in entries {
+                    Self::hash_str(hasher, key);
+                    Self::hash_value(hasher, value);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    #[test]
+    fn evicts_least_recently_used_within_budget() {
+        let row = Value::String("x".repeat(100));
+        let weight = row.weight();
+        let cache = CacheManager::<String, Value>::new(2 * weight + weight / 2);
+
+        cache.insert("users", "1".to_string(), row.clone(), None, 0);
+        cache.insert("users", "2".to_string(), row.clone(), None, 0);
+        cache.get("users", &"1".to_string());
+        cache.insert("themes", "3".to_string(), row.clone(), None, 0);
+
+        assert!(cache.get("users", &"1".to_string()).is_some());
+        assert!(cache.get("users", &"2".to_string()).is_none());
+        assert!(cache.get("themes", &"3".to_string()).is_some());
+
+        // bigger than the whole budget, never cached
+        let huge = Value::String("x".repeat(1000));
+        cache.insert("users", "4".to_string(), huge, None, 0);
+        assert!(cache.get("users", &"4".to_string()).is_none());
+        assert!(cache.get("users", &"1".to_string()).is_some());
+    }
+
+    #[test]
+    fn evicts_expired_entries() {
+        let cache = CacheManager::<String, Value>::new(1024 * 1024);
+        let row = Value::String("x".repeat(10));
+
+        cache.insert(
+            "users",
+            "1".to_string(),
+            row.clone(),
+            Some(Duration::ZERO),
+            0,
+        );
+        cache.insert(
+            "users",
+            "2".to_string(),
+            row,
+            Some(Duration::from_secs(60)),
+            0,
+        );
+
+        assert_eq!(cache.get("users", &"1".to_string()), None);
+        assert!(cache.get("users", &"2".to_string()).is_some());
+    }
+
+    #[test]
+    fn counts_inserts_hits_and_misses_per_table() {
+        let cache = CacheManager::<String, Value>::new(1024 * 1024);
+        let (short, long) = (Value::String("x".repeat(10)), Value::String("x".repeat(20)));
+
+        cache.insert("users", "1".to_string(), short.clone(), None, 0);
+        cache.insert("users", "2".to_string(), long.clone(), None, 0);
+        cache.insert("themes", "3".to_string(), short.clone(), None, 0);
+
+        let stats = cache.stats();
+        assert_eq!(stats.total.inserts, 3);
+        assert_eq!(stats.total.entries, 3);
+        assert_eq!(stats.total.bytes, 2 * short.weight() + long.weight());
+        assert_eq!(stats.tables["users"].entries, 2);
+        assert_eq!(stats.tables["users"].bytes, short.weight() + long.weight());
+        assert_eq!(stats.tables["themes"].bytes, short.weight());
+
+        // replacing an entry doesn't count it twice
+        cache.insert("users", "1".to_string(), long.clone(), None, 0);
+        let stats = cache.stats();
+        assert_eq!(stats.total.entries, 3);
+        assert_eq!(stats.total.bytes, 2 * long.weight() + short.weight());
+
+        assert!(cache.get("users", &"1".to_string()).is_some());
+        assert!(cache.get("users", &"9".to_string()).is_none());
+
+        let stats = cache.stats();
+        assert_eq!((stats.total.hits, stats.total.misses), (1, 1));
+        assert_eq!(
+            (stats.tables["users"].hits, stats.tables["users"].misses),
+            (1, 1)
+        );
+        assert_eq!(stats.tables["themes"].hits, 0);
+    }
+
+    #[test]
+    fn counts_evictions_of_the_table_they_hit() {
+        let row = Value::String("x".repeat(100));
+        let weight = row.weight();
+        let cache = CacheManager::<String, Value>::new(2 * weight);
+
+        cache.insert("users", "1".to_string(), row.clone(), None, 0);
+        cache.insert("themes", "2".to_string(), row.clone(), None, 0);
+        cache.insert("themes", "3".to_string(), row.clone(), None, 0);
+
+        let stats = cache.stats();
+        assert_eq!(stats.total.evictions, 1);
+        assert_eq!(stats.total.bytes, 2 * weight);
+        assert_eq!(stats.tables["users"].evictions, 1);
+        assert_eq!(stats.tables["users"].entries, 0);
+        assert_eq!(stats.tables["users"].bytes, 0);
+        assert_eq!(stats.tables["themes"].evictions, 0);
+
+        // expired entries count as evicted when they are found
+        cache.insert("users", "4".to_string(), row, Some(Duration::ZERO), 0);
+        cache.get("users", &"4".to_string());
+        assert_eq!(cache.stats().tables["users"].evictions, 2);
+    }
+
+    #[test]
+    fn counts_invalidated_entries() {
+        let cache = CacheManager::<String, Value>::new(1024 * 1024);
+        let row = Value::String("x".repeat(10));
+
+        cache.insert("users", "1".to_string(), row.clone(), None, 0);
+        cache.insert("users", "2".to_string(), row.clone(), None, 0);
+        cache.insert("themes", "3".to_string(), row.clone(), None, 0);
+
+        cache.invalidate_table("users");
+
+        assert!(cache.get("users", &"1".to_string()).is_none());
+        assert!(cache.get("themes", &"3".to_string()).is_some());
+
+        let stats = cache.stats();
+        assert_eq!(stats.total.invalidations, 2);
+        assert_eq!(stats.total.entries, 1);
+        assert_eq!(stats.total.bytes, row.weight());
+        assert_eq!(stats.tables["users"].invalidations, 2);
+        assert_eq!(stats.tables["users"].entries, 0);
+
+        cache.flush();
+
+        let stats = cache.stats();
+        assert_eq!(stats.total.invalidations, 3);
+        assert_eq!((stats.total.entries, stats.total.bytes), (0, 0));
+        assert!(stats
+            .tables
+            .values()
+            .all(|table| table.entries == 0 && table.bytes == 0));
+    }
+
+    #[test]
+    fn drops_values_read_before_an_invalidation() {
+        let cache = CacheManager::<String, Value>::new(1024 * 1024);
+        let row = Value::String("x".repeat(10));
+
+        let users = cache.generation("users");
+        cache.invalidate_table("users");
+        cache.insert("users", "1".to_string(), row.clone(), None, users);
+        assert!(cache.get("users", &"1".to_string()).is_none());
+
+        // a flush invalidates tables that had nothing cached too
+        let themes = cache.generation("themes");
+        cache.flush();
+        cache.insert("themes", "2".to_string(), row.clone(), None, themes);
+        assert!(cache.get("themes", &"2".to_string()).is_none());
+
+        // other tables are not affected
+        let themes = cache.generation("themes");
+        cache.invalidate_table("users");
+        cache.insert("themes", "2".to_string(), row, None, themes);
+        assert!(cache.get("themes", &"2".to_string()).is_some());
+    }
+
+    #[test]
+    fn cache_key_ignores_object_key_order() {
+        let query = "SELECT * FROM users WHERE data = $1";
+
+        assert_eq!(
+            CacheKey::generate_cache_key("users", query, &[json!({ "a": 1, "b": [true, null] })]),
+            CacheKey::generate_cache_key("users", query, &[json!({ "b": [true, null], "a": 1 })])
+        );
+    }
+
+    #[test]
+    fn cache_key_tells_parameters_apart() {
+        let query = "SELECT * FROM users WHERE uid = $1";
+        let one = CacheKey::generate_cache_key("users", query, &[json!(1)]);
+
+        for other in [
+            vec![json!(1.0)],
+            vec![json!("1")],
+            vec![json!(true)],
+            vec![json!(1), json!(1)],
+            Vec::new(),
+        ] {
+            assert_ne!(one, CacheKey::generate_cache_key("users", query, &other));
+        }
+
+        assert_eq!(
+            one,
+            CacheKey::generate_cache_key("users", query, &[json!(1)])
+        );
+        assert_ne!(
+            one,
+            CacheKey::generate_cache_key("themes", query, &[json!(1)])
+        );
+        assert_ne!(
+            CacheKey::generate_cache_key("users", query, &[json!(["a", "b"])]),
+            CacheKey::generate_cache_key("users", query, &[json!(["ab"])])
+        );
+        assert_ne!(
+            CacheKey::generate_cache_key("users", "SELECT 1", &[json!("2")]),
+            CacheKey::generate_cache_key("users", "SELECT 12", &[json!("")])
+        );
+    }
+}
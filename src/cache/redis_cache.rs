@@ -0,0 +1,256 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use futures_util::StreamExt as _;
+use redis::aio::ConnectionManager;
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use tokio::sync::OnceCell;
+use tokio::time::sleep;
+
+use super::{CacheBackend, CacheKey, CacheManager, CacheStats, CachedRows};
+use crate::config::CacheConfig;
+use crate::instance;
+use crate::tokio_spawner::TokioSpawner;
+
+/// Invalidation published to every instance sharing the redis channel.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Invalidation {
+    Table { origin: String, table: String },
+    Flush { origin: String },
+}
+
+/// Keeps a local cache per instance and broadcasts invalidations through redis
+/// pub/sub, so a write on one instance evicts the stale entries on all others.
+///
+/// While it is not subscribed, the invalidations of the other instances don't
+/// reach it, so the local cache is bypassed until it is again. Both that and a
+/// failed publish, which leaves the other instances stale, mark it unhealthy
+/// in the stats.
+///
+/// Creating it spawns the subscriber, so it has to happen inside the runtime.
+pub struct RedisCache {
+    local: Arc<CacheManager<CacheKey, CachedRows>>,
+    client: redis::Client,
+    channel: String,
+    instance_id: String,
+    publisher: OnceCell<ConnectionManager>,
+    subscribed: Arc<AtomicBool>,
+    /// whether the last invalidation reached redis
+    published: AtomicBool,
+}
+
+impl RedisCache {
+    pub fn new(cache_config: &CacheConfig) -> Result<Self> {
+        Self::connect(cache_config, instance::id().to_string())
+    }
+
+    fn connect(cache_config: &CacheConfig, instance_id: String) -> Result<Self> {
+        let client = redis::Client::open(cache_config.redis_url.as_str())?;
+        let local = Arc::new(CacheManager::new(cache_config.max_bytes));
+        let subscribed = Arc::new(AtomicBool::new(false));
+
+        TokioSpawner::spawn(Self::subscribe(
+            client.clone(),
+            cache_config.redis_channel.clone(),
+            instance_id.clone(),
+            local.clone(),
+            subscribed.clone(),
+        ));
+
+        Ok(Self {
+            local,
+            client,
+            channel: cache_config.redis_channel.clone(),
+            instance_id,
+            publisher: OnceCell::new(),
+            subscribed,
+            published: AtomicBool::new(true),
+        })
+    }
+
+    fn subscribed(&self) -> bool {
+        self.subscribed.load(Ordering::Relaxed)
+    }
+
+    async fn publish(&self, invalidation: Invalidation) {
+        let result = async {
+            let publisher = self
+                .publisher
+                .get_or_try_init(|| ConnectionManager::new(self.client.clone()))
+                .await?;
+            let payload = serde_json::to_string(&invalidation)?;
+
+            // the connection manager reconnects on its own, cloning it is cheap
+            let mut connection = publisher.clone();
+            let _: () = connection.publish(&self.channel, payload).await?;
+
+            anyhow::Ok(())
+        }
+        .await;
+
+        if let Err(e) = &result {
+            eprintln!("error: failed to publish cache invalidation: {}", e);
+        }
+
+        self.published.store(result.is_ok(), Ordering::Relaxed);
+    }
+
+    /// Applies the invalidations of the other instances until the process exits.
+    async fn subscribe(
+        client: redis::Client,
+        channel: String,
+        instance_id: String,
+        local: Arc<CacheManager<CacheKey, CachedRows>>,
+        subscribed: Arc<AtomicBool>,
+    ) {
+        let mut backoff = Duration::from_secs(1);
+
+        loop {
+            match client.get_async_pubsub().await {
+                Ok(mut pubsub) => match pubsub.subscribe(&channel).await {
+                    Ok(()) => {
+                        // invalidations sent while we were not subscribed are lost
+                        local.flush();
+                        subscribed.store(true, Ordering::Relaxed);
+                        backoff = Duration::from_secs(1);
+
+                        let mut messages = pubsub.into_on_message();
+
+                        while let Some(message) = messages.next().await {
+                            let Ok(payload) = message.get_payload::<String>() else {
+                                continue;
+                            };
+
+                            match serde_json::from_str::<Invalidation>(&payload) {
+                                Ok(Invalidation::Table { origin, table }) => {
+                                    if origin != instance_id {
+                                        local.invalidate_table(&table);
+                                    }
+                                }
+                                Ok(Invalidation::Flush { origin }) => {
+                                    if origin != instance_id {
+                                        local.flush();
+                                    }
+                                }
+                                Err(e) => eprintln!("error: invalid cache invalidation: {}", e),
+                            }
+                        }
+
+                        subscribed.store(false, Ordering::Relaxed);
+                        eprintln!("error: redis invalidation subscription closed");
+                    }
+                    Err(e) => eprintln!("error: failed to subscribe to {}: {}", channel, e),
+                },
+                Err(e) => eprintln!("error: failed to connect to redis: {}", e),
+            }
+
+            sleep(backoff).await;
+            backoff = (backoff * 2).min(Duration::from_secs(60));
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl CacheBackend for RedisCache {
+    fn get(&self, key: &CacheKey) -> Option<CachedRows> {
+        if !self.subscribed() {
+            return None;
+        }
+
+        CacheBackend::get(self.local.as_ref(), key)
+    }
+
+    fn insert(&self, key: CacheKey, value: CachedRows, ttl: Option<Duration>, generation: u64) {
+        if !self.subscribed() {
+            return;
+        }
+
+        CacheBackend::insert(self.local.as_ref(), key, value, ttl, generation)
+    }
+
+    fn generation(&self, table_name: &str) -> u64 {
+        CacheBackend::generation(self.local.as_ref(), table_name)
+    }
+
+    async fn invalidate_table(&self, table_name: &str) {
+        self.local.invalidate_table(table_name);
+
+        self.publish(Invalidation::Table {
+            origin: self.instance_id.clone(),
+            table: table_name.to_string(),
+        })
+        .await;
+    }
+
+    async fn flush(&self) {
+        self.local.flush();
+
+        self.publish(Invalidation::Flush {
+            origin: self.instance_id.clone(),
+        })
+        .await;
+    }
+
+    fn stats(&self) -> CacheStats {
+        CacheStats {
+            healthy: self.subscribed() && self.published.load(Ordering::Relaxed),
+            ..self.local.stats()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use tokio::time::timeout;
+
+    use super::*;
+
+    /// Needs a redis server, skipped unless `REDIS_URL` points at one.
+    #[tokio::test]
+    async fn invalidates_the_other_instances() {
+        let Ok(redis_url) = std::env::var("REDIS_URL") else {
+            return;
+        };
+
+        let cache_config = CacheConfig {
+            redis_url,
+            redis_channel: format!("acid4sigmas-db-api:test:{}", std::process::id()),
+            ..Default::default()
+        };
+        let writer = RedisCache::connect(&cache_config, "writer".to_string()).unwrap();
+        let reader = RedisCache::connect(&cache_config, "reader".to_string()).unwrap();
+
+        let subscribed = async {
+            while !(writer.stats().healthy && reader.stats().healthy) {
+                sleep(Duration::from_millis(10)).await;
+            }
+        };
+        timeout(Duration::from_secs(5), subscribed)
+            .await
+            .expect("both instances subscribe");
+
+        let key = CacheKey::generate_cache_key("users", "SELECT * FROM users", &[]);
+        for cache in [&writer, &reader] {
+            cache.insert(key.clone(), vec![json!({ "uid": 1 })], None, 0);
+            assert!(cache.get(&key).is_some());
+        }
+
+        writer.invalidate_table("users").await;
+        assert!(writer.get(&key).is_none());
+        assert!(writer.stats().healthy);
+
+        let invalidated = async {
+            while reader.get(&key).is_some() {
+                sleep(Duration::from_millis(10)).await;
+            }
+        };
+        timeout(Duration::from_secs(5), invalidated)
+            .await
+            .expect("the invalidation reaches the other instance");
+    }
+}
@@ -0,0 +1,191 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::broadcast;
+
+type SharedResult<T> = Result<T, Arc<anyhow::Error>>;
+
+/// Error of a coalesced call, shared between the caller that ran it and every
+/// caller that waited on it.
+#[derive(Debug, Clone)]
+pub struct SharedError(pub Arc<anyhow::Error>);
+
+impl fmt::Display for SharedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+impl std::error::Error for SharedError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&**self.0)
+    }
+}
+
+/// Coalesces concurrent calls for the same key, so only one of them does the
+/// work and the others wait for and share its result.
+pub struct SingleFlight<K, T> {
+    in_flight: Mutex<HashMap<K, broadcast::Sender<SharedResult<T>>>>,
+}
+
+impl<K, T> SingleFlight<K, T>
+where
+    K: Eq + Hash + Clone,
+    T: Clone,
+{
+    pub fn new() -> Self {
+        Self {
+            in_flight: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Runs `work` unless a call for `key` is already in flight, in which case
+    /// its result is awaited instead. Results are not kept once every waiter has
+    /// received them.
+    pub async fn run<F, Fut>(&self, key: K, work: F) -> anyhow::Result<T>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = anyhow::Result<T>>,
+    {
+        let receiver = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+
+            match in_flight.get(&key) {
+                Some(sender) => Some(sender.subscribe()),
+                None => {
+                    let (sender, _) = broadcast::channel(1);
+                    in_flight.insert(key.clone(), sender);
+                    None
+                }
+            }
+        };
+
+        if let Some(mut receiver) = receiver {
+            return match receiver.recv().await {
+                Ok(result) => result.map_err(|e| SharedError(e).into()),
+                // the running call was cancelled before it produced a result
+                Err(_) => work().await,
+            };
+        }
+
+        let flight = Flight {
+            single_flight: self,
+            key: Some(key),
+        };
+
+        let result = work().await.map_err(Arc::new);
+
+        if let Some(sender) = flight.finish() {
+            let _ = sender.send(result.clone());
+        }
+
+        result.map_err(|e| SharedError(e).into())
+    }
+}
+
+impl<K, T> Default for SingleFlight<K, T>
+where
+    K: Eq + Hash + Clone,
+    T: Clone,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Unregisters an in-flight call, also when the future running it is dropped.
+struct Flight<'a, K, T>
+where
+    K: Eq + Hash,
+{
+    single_flight: &'a SingleFlight<K, T>,
+    key: Option<K>,
+}
+
+impl<K, T> Flight<'_, K, T>
+where
+    K: Eq + Hash,
+{
+    fn finish(mut self) -> Option<broadcast::Sender<SharedResult<T>>> {
+        let key = self.key.take()?;
+        self.single_flight.in_flight.lock().unwrap().remove(&key)
+    }
+}
+
+impl<K, T> Drop for Flight<'_, K, T>
+where
+    K: Eq + Hash,
+{
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            self.single_flight.in_flight.lock().unwrap().remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use futures_util::poll;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn runs_concurrent_calls_for_a_key_once() {
+        let flights = SingleFlight::<&str, u32>::new();
+        let calls = AtomicUsize::new(0);
+
+        let work = || async {
+            calls.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            Ok(7)
+        };
+
+        let (first, second, other) = tokio::join!(
+            flights.run("users", work),
+            flights.run("users", work),
+            flights.run("themes", work)
+        );
+
+        assert_eq!((first.unwrap(), second.unwrap(), other.unwrap()), (7, 7, 7));
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        // nothing is kept once the call is done
+        flights.run("users", work).await.unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn shares_errors_with_waiters() {
+        let flights = SingleFlight::<&str, u32>::new();
+
+        let fail = || async {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            Err(anyhow::anyhow!("statement timeout"))
+        };
+
+        let (first, second) = tokio::join!(flights.run("users", fail), flights.run("users", fail));
+
+        assert_eq!(first.unwrap_err().to_string(), "statement timeout");
+        assert_eq!(second.unwrap_err().to_string(), "statement timeout");
+    }
+
+    #[tokio::test]
+    async fn waiters_run_the_work_when_the_leader_is_dropped() {
+        let flights = SingleFlight::<&str, u32>::new();
+
+        let mut leader = Box::pin(flights.run("users", std::future::pending));
+        assert!(poll!(&mut leader).is_pending());
+
+        let mut waiter = Box::pin(flights.run("users", || async { Ok(2) }));
+        assert!(poll!(&mut waiter).is_pending());
+
+        drop(leader);
+        assert_eq!(waiter.await.unwrap(), 2);
+    }
+}
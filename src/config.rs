@@ -0,0 +1,396 @@
+use anyhow::{Context, Result};
+use jsonwebtoken::Algorithm;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+pub static CONFIG: OnceLock<Config> = OnceLock::new();
+
+/// Runtime configuration loaded from `Config.toml`.
+///
+/// Every section falls back to its defaults, so a missing file or a missing
+/// section behaves like an empty one.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub auth: AuthConfig,
+    pub cache: CacheConfig,
+    pub notify: NotifyConfig,
+    pub query: QueryConfig,
+    pub rate_limit: RateLimitConfig,
+    pub websocket: WebSocketConfig,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct AuthConfig {
+    /// file mapping the roles of tokens to what they may access
+    pub policy_file: String,
+    /// whether `/db?token=` is accepted, such tokens end up in access logs
+    /// and browser history
+    pub query_token: bool,
+    /// whether tokens with a `jti` have to be listed in `auth_tokens`
+    pub revocation: bool,
+    /// how long a token's row is trusted to still (not) exist
+    pub revocation_cache_secs: u64,
+    pub jwt: JwtConfig,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            policy_file: "Policy.toml".to_string(),
+            query_token: true,
+            revocation: true,
+            revocation_cache_secs: 10,
+            jwt: JwtConfig::default(),
+        }
+    }
+}
+
+/// Keys tokens are verified with, and the claims they have to carry.
+///
+/// Without any keys tokens are verified with the secret from `Secrets.toml`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct JwtConfig {
+    pub keys: Vec<KeyConfig>,
+    /// jwks files with further keys, each needs a `kid` and an `alg`
+    pub jwks_files: Vec<String>,
+    /// kid of the key that verifies tokens without a kid. It is no signing
+    /// key, this service never issues tokens so it has none
+    pub default_kid: Option<String>,
+    /// accepted `iss` claims, not checked if empty
+    pub issuer: Vec<String>,
+    /// accepted `aud` claims, not checked if empty
+    pub audience: Vec<String>,
+    /// how many seconds `exp` and `nbf` may be off
+    pub leeway_secs: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct KeyConfig {
+    pub kid: String,
+    #[serde(default)]
+    pub algorithm: Algorithm,
+    /// file holding the secret of a HS256/HS384/HS512 key
+    pub secret_file: Option<String>,
+    /// file holding the public key of a RS256/RS384/RS512 or EdDSA key
+    pub pem_file: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheBackendKind {
+    /// a cache local to this process
+    #[default]
+    Memory,
+    /// a local cache per instance, kept consistent through redis pub/sub invalidations
+    Redis,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct CacheConfig {
+    pub backend: CacheBackendKind,
+    pub redis_url: String,
+    /// pub/sub channel the instances exchange invalidations on
+    pub redis_channel: String,
+    /// approximate upper bound for all cached values, in bytes
+    pub max_bytes: usize,
+    /// how long an entry stays valid, `0` keeps entries until they are evicted
+    pub default_ttl_secs: u64,
+    pub tables: HashMap<String, TableCachePolicy>,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            backend: CacheBackendKind::Memory,
+            redis_url: "redis://127.0.0.1:6379/".to_string(),
+            redis_channel: "acid4sigmas-db-api:cache".to_string(),
+            max_bytes: 64 * 1024 * 1024,
+            default_ttl_secs: 60,
+            tables: HashMap::new(),
+        }
+    }
+}
+
+impl CacheConfig {
+    /// Returns the policy for `table_name`, or the default policy if the table has none.
+    pub fn policy(&self, table_name: &str) -> TableCachePolicy {
+        self.tables.get(table_name).cloned().unwrap_or_default()
+    }
+
+    /// Returns how long entries of `table_name` may be served from the cache.
+    pub fn ttl(&self, table_name: &str) -> Option<Duration> {
+        let secs = self
+            .policy(table_name)
+            .ttl_secs
+            .unwrap_or(self.default_ttl_secs);
+
+        if secs == 0 {
+            None
+        } else {
+            Some(Duration::from_secs(secs))
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct TableCachePolicy {
+    pub enabled: bool,
+    pub ttl_secs: Option<u64>,
+}
+
+impl Default for TableCachePolicy {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            ttl_secs: None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct WebSocketConfig {
+    /// requests a single session may run at the same time
+    pub max_in_flight: usize,
+    /// largest single frame, in bytes
+    pub max_frame_size: usize,
+    /// largest message assembled from continuation frames, in bytes
+    pub max_message_size: usize,
+    /// how often the server pings the client
+    pub ping_interval_secs: u64,
+    /// sessions that sent nothing, not even a pong, for this long are closed
+    pub idle_timeout_secs: u64,
+    /// live subscriptions a single session may hold at the same time
+    pub max_subscriptions: usize,
+    /// rows a single write may send to subscriptions, bigger writes make them
+    /// reload their snapshot instead
+    pub max_event_rows: usize,
+    /// transactions a single session may have open at the same time
+    pub max_transactions: usize,
+    /// transactions no request used for this long are rolled back
+    pub transaction_timeout_secs: u64,
+    /// smallest message that is deflated on the `a4s.msgpack+deflate`
+    /// subprotocol, in bytes
+    pub compress_min_bytes: usize,
+    /// deflate level from 0 (none) to 9 (smallest)
+    pub compress_level: u32,
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        Self {
+            max_in_flight: 16,
+            max_frame_size: 64 * 1024,
+            max_message_size: 1024 * 1024,
+            ping_interval_secs: 20,
+            idle_timeout_secs: 60,
+            max_subscriptions: 32,
+            max_event_rows: 1000,
+            max_transactions: 2,
+            transaction_timeout_secs: 30,
+            compress_min_bytes: 1024,
+            compress_level: 6,
+        }
+    }
+}
+
+/// Bounds on how much a single request may cost the database.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct QueryConfig {
+    /// limit of retrieves that don't set one, 0 for none
+    pub default_limit: u64,
+    /// largest limit a retrieve may set, 0 for no maximum
+    pub max_limit: u64,
+    /// most rows a single bulk insert may write, 0 for no maximum
+    pub max_bulk_rows: usize,
+    /// most steps a single batch may have, 0 for no maximum
+    pub max_batch_steps: usize,
+    /// most rows the steps of a single batch may write together, 0 for no
+    /// maximum
+    pub max_batch_rows: usize,
+    /// longest a single statement may run, 0 for no timeout
+    pub statement_timeout_ms: u64,
+    /// longest a statement may wait for a lock, 0 for no timeout
+    pub lock_timeout_ms: u64,
+    /// timeouts per action, over the ones above
+    pub actions: HashMap<String, QueryTimeouts>,
+    /// limits per table, over the ones above
+    pub tables: HashMap<String, TableQueryPolicy>,
+}
+
+impl Default for QueryConfig {
+    fn default() -> Self {
+        Self {
+            default_limit: 100,
+            max_limit: 1000,
+            max_bulk_rows: 1000,
+            max_batch_steps: 50,
+            max_batch_rows: 1000,
+            statement_timeout_ms: 5000,
+            lock_timeout_ms: 1000,
+            actions: HashMap::new(),
+            tables: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct QueryTimeouts {
+    pub statement_timeout_ms: Option<u64>,
+    pub lock_timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct TableQueryPolicy {
+    pub default_limit: Option<u64>,
+    pub max_limit: Option<u64>,
+    pub max_bulk_rows: Option<usize>,
+    /// whether updates and deletes need a `where` filter
+    pub require_filters: bool,
+}
+
+/// Limits of a single table, `0` for none.
+#[derive(Debug, Clone, Copy)]
+pub struct QueryLimits {
+    pub default_limit: u64,
+    pub max_limit: u64,
+    pub max_bulk_rows: usize,
+    pub require_filters: bool,
+}
+
+impl QueryConfig {
+    /// Returns the limits of `table_name`, with the defaults filled in.
+    pub fn limits(&self, table_name: &str) -> QueryLimits {
+        let policy = self.tables.get(table_name).cloned().unwrap_or_default();
+
+        QueryLimits {
+            default_limit: policy.default_limit.unwrap_or(self.default_limit),
+            max_limit: policy.max_limit.unwrap_or(self.max_limit),
+            max_bulk_rows: policy.max_bulk_rows.unwrap_or(self.max_bulk_rows),
+            require_filters: policy.require_filters,
+        }
+    }
+
+    /// Returns the statement and lock timeout of `action`, in milliseconds.
+    pub fn timeouts(&self, action: &str) -> (u64, u64) {
+        let timeouts = self.actions.get(action).cloned().unwrap_or_default();
+
+        (
+            timeouts
+                .statement_timeout_ms
+                .unwrap_or(self.statement_timeout_ms),
+            timeouts.lock_timeout_ms.unwrap_or(self.lock_timeout_ms),
+        )
+    }
+}
+
+/// How many requests each token subject and peer address may make.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    pub enabled: bool,
+    /// limits of tokens whose roles have none of their own
+    pub default: Limits,
+    /// limits per role, a token gets those of the first of its roles listed
+    pub roles: HashMap<String, Limits>,
+    /// limits per peer address, shared by every token used from it
+    pub peer: Limits,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            default: Limits {
+                reads_per_sec: 50.0,
+                read_burst: 100.0,
+                writes_per_sec: 10.0,
+                write_burst: 20.0,
+                max_concurrent: 32,
+                daily_quota: 0,
+            },
+            roles: HashMap::new(),
+            peer: Limits {
+                reads_per_sec: 200.0,
+                read_burst: 400.0,
+                writes_per_sec: 50.0,
+                write_burst: 100.0,
+                max_concurrent: 0,
+                daily_quota: 0,
+            },
+        }
+    }
+}
+
+/// Limits of a single subject or peer, `0` turns a limit off.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Limits {
+    pub reads_per_sec: f64,
+    /// reads that can be made at once after a pause
+    pub read_burst: f64,
+    pub writes_per_sec: f64,
+    pub write_burst: f64,
+    /// requests running at the same time, across all sessions
+    pub max_concurrent: usize,
+    /// requests per utc day
+    pub daily_quota: u64,
+}
+
+/// Cache invalidation and subscription updates through postgres
+/// `LISTEN`/`NOTIFY`, for writes that bypass this api.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct NotifyConfig {
+    pub enabled: bool,
+    pub channel: String,
+    pub max_backoff_secs: u64,
+}
+
+impl Default for NotifyConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            channel: "acid4sigmas_changes".to_string(),
+            max_backoff_secs: 60,
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file from {:?}", path))?;
+
+        toml::from_str(&content).with_context(|| format!("Failed to parse config file {:?}", path))
+    }
+}
+
+pub fn init_config(path: &str) -> Result<()> {
+    let config = Config::load(Path::new(path))?;
+
+    CONFIG
+        .set(config)
+        .map_err(|_| anyhow::anyhow!("config already initialized"))
+}
+
+/// Returns the loaded config, or the defaults if `init_config` was never called.
+pub fn get() -> &'static Config {
+    CONFIG.get_or_init(Config::default)
+}
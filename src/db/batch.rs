@@ -0,0 +1,412 @@
+use std::collections::HashMap;
+
+use acid4sigmas_models::models::db::{DatabaseAction, DatabaseRequest};
+use anyhow::Result;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sqlx::{Connection, PgConnection};
+
+use super::bulk_insert::BulkInsert;
+use super::changes::PendingChange;
+use super::delete::Delete;
+use super::insert::Insert;
+use super::table::Table;
+use super::update::Update;
+use super::upsert::Upsert;
+use crate::auth::TokenClaims;
+use crate::error::{ApiError, ErrorCode};
+use crate::guards;
+use crate::policy;
+use crate::protocol::RequestId;
+
+/// A list of writes that succeed or fail together.
+#[derive(Debug, Deserialize)]
+pub struct BatchRequest {
+    pub steps: Vec<Value>,
+    /// id of the `Begin` request of the transaction to run the batch in
+    #[serde(default)]
+    pub transaction: Option<RequestId>,
+}
+
+impl BatchRequest {
+    /// Whether the raw request is a batch.
+    pub fn is_batch(value: &Value) -> bool {
+        value.get("action").and_then(Value::as_str) == Some("Batch")
+    }
+}
+
+/// The only step that has no `DatabaseAction`.
+#[derive(Debug, Deserialize)]
+struct UpsertStep {
+    table: String,
+    values: HashMap<String, Value>,
+    /// columns of the unique constraint that decides between insert and update
+    conflict: Vec<String>,
+}
+
+pub struct Batch;
+
+impl Batch {
+    /// Runs the steps in order for the holder of `claims`, returns the rows
+    /// each step wrote together with the changes to apply once the surrounding
+    /// transaction commits.
+    ///
+    /// Every step is bounded by the guards and authorized once its references
+    /// are resolved, so the policy sees the table and values it really writes.
+    /// The steps run in a nested transaction, so a failing step undoes the
+    /// steps before it even inside a session's transaction. The error of a
+    /// failing step carries its index.
+    pub async fn run(
+        conn: &mut PgConnection,
+        steps: Vec<Value>,
+        claims: &TokenClaims,
+    ) -> Result<(Vec<Value>, Vec<PendingChange>)> {
+        if steps.is_empty() {
+            return Err(ApiError::validation("A batch needs at least one step.").into());
+        }
+
+        guards::check_batch(&steps)?;
+
+        let mut txn = conn.begin().await?;
+
+        let policy = policy::get();
+
+        // masked, so neither the results nor the references of later steps
+        // reveal hidden columns
+        let mut written: Vec<Vec<Value>> = Vec::new();
+        let mut changes = Vec::new();
+
+        for (index, mut step) in steps.into_iter().enumerate() {
+            let (change, mask) = async {
+                Self::resolve_references(&mut step, &written)?;
+
+                guards::apply(&mut step)?;
+                let mask = policy.authorize(claims, &mut step)?;
+
+                let change = Self::run_step(&mut *txn, step).await?;
+                anyhow::Ok((change, mask))
+            }
+            .await
+            .map_err(|e| ApiError::from(e).with_step(index))?;
+
+            let mut rows = change.rows().to_vec();
+            mask.apply(&mut rows);
+
+            written.push(rows);
+            changes.push(change);
+        }
+
+        txn.commit().await?;
+
+        let results = written
+            .into_iter()
+            .map(|rows| json!({ "rows": rows }))
+            .collect();
+
+        Ok((results, changes))
+    }
+
+    async fn run_step(conn: &mut PgConnection, step: Value) -> Result<PendingChange> {
+        if step.get("action").and_then(Value::as_str) == Some("Upsert") {
+            let upsert = serde_json::from_value::<UpsertStep>(step)
+                .map_err(|e| ApiError::validation(format!("Failed to parse step: {}", e)))?;
+
+            return Upsert::upsert(conn, &upsert.table, &upsert.values, &upsert.conflict).await;
+        }
+
+        let mut request = serde_json::from_value::<DatabaseRequest>(step)
+            .map_err(|e| ApiError::validation(format!("Failed to parse step: {}", e)))?;
+
+        request
+            .validate()
+            .map_err(|e| ApiError::validation(e.to_string()))?;
+
+        if !Table::exists(&mut *conn, &request.table).await? {
+            return Err(ApiError::new(ErrorCode::UnknownTable, "No such table exists.").into());
+        }
+
+        let table_name = &request.table;
+
+        // every step returns its rows, they are its result and what later
+        // steps refer to
+        match &request.action {
+            DatabaseAction::Insert => {
+                let values = request
+                    .values
+                    .as_ref()
+                    .ok_or_else(|| ApiError::validation("Missing values for insert"))?;
+
+                Insert::insert(conn, table_name, values, true).await
+            }
+            DatabaseAction::BulkInsert => {
+                let bulk_values = request
+                    .bulk_values
+                    .as_ref()
+                    .ok_or_else(|| ApiError::validation("Missing values for insert"))?;
+
+                BulkInsert::bulk_insert(conn, table_name, bulk_values, true).await
+            }
+            DatabaseAction::Update => {
+                let values = request
+                    .values
+                    .clone()
+                    .ok_or_else(|| ApiError::validation("Missing values for update"))?;
+
+                Update::update(conn, table_name, values, request.filters.clone(), true).await
+            }
+            DatabaseAction::Delete(action) => {
+                Delete::delete(
+                    conn,
+                    table_name,
+                    action.clone(),
+                    request.filters.clone(),
+                    true,
+                )
+                .await
+            }
+            DatabaseAction::Retrieve => {
+                Err(ApiError::validation("Batches can only contain writes.").into())
+            }
+        }
+    }
+
+    /// Replaces every `{ "$step": <index>, "column": <name> }` in `step` with
+    /// that column of a row an earlier step wrote, the first one unless a
+    /// `"row"` index is given.
+    ///
+    /// A reference can only stand for a value written to or compared with a
+    /// column, in `values`, `bulk_values` or `filters.where`. Anywhere else it
+    /// could change what the policy has already checked, so it is rejected.
+    fn resolve_references(step: &mut Value, written: &[Vec<Value>]) -> Result<(), ApiError> {
+        let Value::Object(step) = step else {
+            return Self::no_references(step);
+        };
+
+        for (key, value) in step.iter_mut() {
+            match key.as_str() {
+                "values" => Self::resolve_row(value, written)?,
+                "bulk_values" => match value {
+                    Value::Array(rows) => {
+                        for row in rows {
+                            Self::resolve_row(row, written)?;
+                        }
+                    }
+                    row => Self::resolve_row(row, written)?,
+                },
+                "filters" => match value {
+                    Value::Object(filters) => {
+                        for (key, value) in filters.iter_mut() {
+                            if key == "where" {
+                                Self::resolve_row(value, written)?;
+                            } else {
+                                Self::no_references(value)?;
+                            }
+                        }
+                    }
+                    filters => Self::no_references(filters)?,
+                },
+                _ => Self::no_references(value)?,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Resolves the references among the column values of `row`.
+    fn resolve_row(row: &mut Value, written: &[Vec<Value>]) -> Result<(), ApiError> {
+        if Self::is_reference(row) {
+            return Err(Self::misplaced_reference());
+        }
+
+        let Value::Object(row) = row else {
+            return Self::no_references(row);
+        };
+
+        for value in row.values_mut() {
+            if Self::is_reference(value) {
+                *value = Self::resolve(value, written)?;
+            } else {
+                Self::no_references(value)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn resolve(reference: &Value, written: &[Vec<Value>]) -> Result<Value, ApiError> {
+        let step = reference.get("$step").and_then(Value::as_u64);
+        let column = reference.get("column").and_then(Value::as_str);
+        let row = reference.get("row").and_then(Value::as_u64).unwrap_or(0);
+
+        let (Some(step), Some(column)) = (step, column) else {
+            return Err(ApiError::validation(
+                "A reference needs a \"$step\" index and a \"column\".",
+            ));
+        };
+
+        let rows = written.get(step as usize).ok_or_else(|| {
+            ApiError::validation("A reference can only refer to an earlier step.")
+        })?;
+
+        let resolved = rows
+            .get(row as usize)
+            .ok_or_else(|| ApiError::validation("The referenced step wrote no such row."))?
+            .get(column)
+            .ok_or_else(|| {
+                ApiError::new(
+                    ErrorCode::UnknownColumn,
+                    "The referenced row has no such column.",
+                )
+                .with_column(column)
+            })?;
+
+        Ok(resolved.clone())
+    }
+
+    /// Rejects `value` if it contains a reference anywhere.
+    fn no_references(value: &Value) -> Result<(), ApiError> {
+        match value {
+            Value::Object(map) if map.contains_key("$step") => Err(Self::misplaced_reference()),
+            Value::Object(map) => map.values().try_for_each(Self::no_references),
+            Value::Array(values) => values.iter().try_for_each(Self::no_references),
+            _ => Ok(()),
+        }
+    }
+
+    fn is_reference(value: &Value) -> bool {
+        value
+            .as_object()
+            .is_some_and(|map| map.contains_key("$step"))
+    }
+
+    fn misplaced_reference() -> ApiError {
+        ApiError::validation(
+            "References can only stand for values in values, bulk_values or filters.where.",
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn resolves_values_rows_and_filters() {
+        let written = vec![
+            vec![json!({ "uid": 7 })],
+            vec![json!({ "id": 1 }), json!({ "id": 2 })],
+        ];
+
+        let mut step = json!({
+            "table": "users",
+            "action": "Update",
+            "values": { "uid": { "$step": 0, "column": "uid" }, "username": "a" },
+            "filters": { "where": { "id": { "$step": 1, "column": "id", "row": 1 } }, "limit": 1 }
+        });
+        Batch::resolve_references(&mut step, &written).unwrap();
+
+        assert_eq!(step["values"], json!({ "uid": 7, "username": "a" }));
+        assert_eq!(step["filters"], json!({ "where": { "id": 2 }, "limit": 1 }));
+
+        let mut step = json!({
+            "table": "themes",
+            "action": "BulkInsert",
+            "bulk_values": [
+                { "uid": { "$step": 0, "column": "uid" } },
+                { "uid": { "$step": 1, "column": "id" } }
+            ]
+        });
+        Batch::resolve_references(&mut step, &written).unwrap();
+
+        assert_eq!(step["bulk_values"], json!([{ "uid": 7 }, { "uid": 1 }]));
+
+        // values that are no references are left alone
+        let step = json!({
+            "table": "themes",
+            "action": "Insert",
+            "values": { "uid": 1, "settings": { "dark": true }, "tags": ["a"] }
+        });
+        let mut resolved = step.clone();
+        Batch::resolve_references(&mut resolved, &written).unwrap();
+        assert_eq!(resolved, step);
+    }
+
+    #[test]
+    fn rejects_references_outside_column_values() {
+        let written = vec![vec![json!({ "uid": 7, "email": "a@b.c" })]];
+
+        let misplaced = [
+            json!({ "table": { "$step": 0, "column": "email" }, "action": "Insert" }),
+            json!({ "table": "users", "action": { "$step": 0, "column": "email" } }),
+            json!({ "table": "users", "action": "Insert", "values": { "$step": 0, "column": "uid" } }),
+            json!({
+                "table": "users",
+                "action": "Retrieve",
+                "filters": { "order_by": { "column": { "$step": 0, "column": "email" } } }
+            }),
+            json!({
+                "table": "users",
+                "action": "Insert",
+                "values": { "settings": { "uid": { "$step": 0, "column": "uid" } } }
+            }),
+            json!({
+                "table": "users",
+                "action": "Insert",
+                "values": { "tags": [{ "$step": 0, "column": "uid" }] }
+            }),
+        ];
+
+        for mut step in misplaced {
+            let error = Batch::resolve_references(&mut step, &written).unwrap_err();
+            assert_eq!(error.code, ErrorCode::Validation, "{}", step);
+        }
+    }
+
+    #[test]
+    fn rejects_unresolvable_references() {
+        let written = vec![vec![json!({ "uid": 7 })]];
+
+        // only earlier steps, and rows and columns they wrote
+        for reference in [
+            json!({ "$step": 1, "column": "uid" }),
+            json!({ "$step": 0, "column": "uid", "row": 1 }),
+            json!({ "$step": 0, "column": "email" }),
+            json!({ "$step": 0 }),
+        ] {
+            let mut step =
+                json!({ "table": "users", "action": "Insert", "values": { "uid": reference } });
+            assert!(Batch::resolve_references(&mut step, &written).is_err());
+        }
+    }
+
+    #[test]
+    fn hidden_columns_can_not_be_referenced() {
+        let policy: crate::policy::Policy = toml::from_str(
+            r#"
+            [roles.frontend.tables.auth_users]
+            actions = ["Retrieve"]
+            hidden = ["password_hash"]
+            "#,
+        )
+        .unwrap();
+        let frontend: TokenClaims = serde_json::from_value(json!({ "role": "frontend" })).unwrap();
+
+        let mut retrieve = json!({ "table": "auth_users", "action": "Retrieve" });
+        let mask = policy.authorize(&frontend, &mut retrieve).unwrap();
+
+        let mut rows = vec![json!({ "uid": 7, "password_hash": "x" })];
+        mask.apply(&mut rows);
+        let written = vec![rows];
+
+        let mut step = json!({
+            "table": "users",
+            "action": "Insert",
+            "values": { "password": { "$step": 0, "column": "password_hash" } }
+        });
+        let error = Batch::resolve_references(&mut step, &written).unwrap_err();
+        assert_eq!(error.code, ErrorCode::UnknownColumn);
+        assert_eq!(error.column.as_deref(), Some("password_hash"));
+    }
+}
@@ -0,0 +1,151 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use serde::Serialize;
+use serde_json::Value;
+use sqlx::postgres::PgRow;
+use sqlx::PgConnection;
+use tokio::sync::broadcast;
+
+use super::table::Table;
+use crate::cache::CACHE_MANAGER;
+use crate::config;
+use crate::instance;
+
+/// Setting our own writes are tagged with, so the change listener can tell
+/// them apart from writes made outside this api.
+pub const ORIGIN_SETTING: &str = "acid4sigmas.origin";
+
+lazy_static::lazy_static! {
+    /// every change with its rows, for live subscriptions
+    static ref CHANGES: broadcast::Sender<Arc<Change>> = broadcast::channel(1024).0;
+    /// which tables changed, for listeners that don't need the rows
+    static ref TABLES: broadcast::Sender<Arc<Change>> = broadcast::channel(1024).0;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeOp {
+    Insert,
+    Update,
+    Upsert,
+    Delete,
+}
+
+/// A write, as seen by live subscriptions.
+#[derive(Debug)]
+pub enum Change {
+    /// rows written through this api, as they are after the write (or before
+    /// it, for deletes)
+    Rows {
+        table: String,
+        op: ChangeOp,
+        rows: Vec<Value>,
+    },
+    /// the table was written to outside this api, which rows changed is unknown
+    Table { table: String },
+    /// anything may have changed, for example because notifications were lost
+    All,
+}
+
+/// Distributes the writes of this instance to the live subscriptions of
+/// every session.
+pub struct ChangeBus;
+
+impl ChangeBus {
+    pub fn subscribe() -> broadcast::Receiver<Arc<Change>> {
+        CHANGES.subscribe()
+    }
+
+    /// Like `subscribe`, but every change only names its table, `Rows`
+    /// arrive as `Table`.
+    pub fn subscribe_tables() -> broadcast::Receiver<Arc<Change>> {
+        TABLES.subscribe()
+    }
+
+    /// Whether anyone listens for the rows of changes, writes don't have to
+    /// return them otherwise.
+    pub fn wants_rows() -> bool {
+        CHANGES.receiver_count() > 0
+    }
+
+    pub fn publish(change: Change) {
+        let table_change = match &change {
+            Change::Rows { table, .. } | Change::Table { table } => Change::Table {
+                table: table.clone(),
+            },
+            Change::All => Change::All,
+        };
+
+        // fails only if nobody is subscribed
+        let _ = TABLES.send(Arc::new(table_change));
+        let _ = CHANGES.send(Arc::new(change));
+    }
+
+    /// Tags the current transaction as written by this instance, so its
+    /// change notification is not applied a second time.
+    pub async fn tag_transaction(conn: &mut PgConnection) -> Result<()> {
+        if !config::get().notify.enabled {
+            return Ok(());
+        }
+
+        sqlx::query("SELECT set_config($1, $2, true)")
+            .bind(ORIGIN_SETTING)
+            .bind(instance::id())
+            .execute(conn)
+            .await?;
+
+        Ok(())
+    }
+}
+
+/// Effects of a write that only become visible once its transaction commits:
+/// invalidating the cached rows of its table and telling subscriptions.
+#[derive(Debug)]
+pub struct PendingChange {
+    table: String,
+    op: ChangeOp,
+    /// the written rows, `None` if the statement did not return them or the
+    /// table has no model to show them with
+    rows: Option<Vec<Value>>,
+}
+
+impl PendingChange {
+    /// `rows` are the rows the statement returned, `None` if it was run
+    /// without `RETURNING`.
+    pub fn new(table_name: &str, op: ChangeOp, rows: Option<&[PgRow]>) -> Self {
+        let rows = rows
+            .filter(|_| Table::has_model(table_name))
+            .map(|rows| Table::models(table_name, rows));
+
+        Self {
+            table: table_name.to_string(),
+            op,
+            rows,
+        }
+    }
+
+    /// The written rows, as returned by the statement.
+    pub fn rows(&self) -> &[Value] {
+        self.rows.as_deref().unwrap_or_default()
+    }
+
+    /// Applies the change, after its transaction committed.
+    pub async fn apply(self) {
+        CACHE_MANAGER.invalidate_table(&self.table).await;
+
+        let change = match self.rows {
+            Some(rows) if rows.is_empty() => return,
+            Some(rows) if rows.len() <= config::get().websocket.max_event_rows => Change::Rows {
+                table: self.table,
+                op: self.op,
+                rows,
+            },
+            // too many rows to send, or we don't know them, subscriptions
+            // reload their snapshot instead
+            _ => Change::Table { table: self.table },
+        };
+
+        ChangeBus::publish(change);
+    }
+}
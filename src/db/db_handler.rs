@@ -1,12 +1,16 @@
+use crate::cache::CacheMode;
 use crate::db::{delete::Delete, retrieve::Retrieve, update::Update};
+use crate::error::{ApiError, ErrorCode};
 
+use super::changes::ChangeBus;
 use super::table::Table;
+use super::transaction::{Scope, SharedTransaction};
 use super::Database;
 use super::{bulk_insert::BulkInsert, insert::Insert};
 use acid4sigmas_models::models::db::{
     DatabaseAction, DatabaseRequest, DatabaseResponse, DeleteAction,
 };
-use anyhow::{anyhow, Context, Result};
+use anyhow::{Context, Result};
 use sqlx::PgPool;
 
 use serde_json::Value;
@@ -14,7 +18,11 @@ use serde_json::Value;
 //pub async fn async_db_hanlder(db_request: DatabaseRequest) {}
 
 pub trait DbHandler {
-    async fn new(db_request: DatabaseRequest) -> Result<Self>
+    async fn new(
+        db_request: DatabaseRequest,
+        cache_mode: CacheMode,
+        transaction: Option<SharedTransaction>,
+    ) -> Result<Self>
     where
         Self: Sized;
     async fn handle_request(&self) -> Result<DatabaseResponse<Value>>;
@@ -27,20 +35,32 @@ pub trait DbHandler {
 
 pub struct DatabaseHandler {
     db_request: DatabaseRequest,
+    cache_mode: CacheMode,
+    /// transaction of the session the request runs in, if any
+    transaction: Option<SharedTransaction>,
     pool: PgPool,
 }
 
 impl DbHandler for DatabaseHandler {
-    async fn new(db_request: DatabaseRequest) -> Result<Self> {
+    async fn new(
+        db_request: DatabaseRequest,
+        cache_mode: CacheMode,
+        transaction: Option<SharedTransaction>,
+    ) -> Result<Self> {
         let pool = Database::get_pool()
             .await
             .context("Failed to get database pool.")?;
 
         if !Table::exists(&pool, &db_request.table).await? {
-            return Err(anyhow!("No such table exists."));
+            return Err(ApiError::new(ErrorCode::UnknownTable, "No such table exists.").into());
         }
 
-        Ok(Self { db_request, pool })
+        Ok(Self {
+            db_request,
+            cache_mode,
+            transaction,
+            pool,
+        })
     }
 
     async fn handle_request(&self) -> Result<DatabaseResponse<serde_json::Value>> {
@@ -58,11 +78,19 @@ impl DbHandler for DatabaseHandler {
             .db_request
             .bulk_values
             .as_ref()
-            .ok_or_else(|| anyhow!("Missing values for insert"))?;
+            .ok_or_else(|| ApiError::validation("Missing values for insert"))?;
         let table_name = &self.db_request.table;
-        let pool = &self.pool;
 
-        BulkInsert::bulk_insert(pool, table_name, bulk_values).await?;
+        let mut scope = Scope::begin(&self.pool, self.transaction.as_ref(), "BulkInsert").await?;
+        let change = BulkInsert::bulk_insert(
+            scope.conn(),
+            table_name,
+            bulk_values,
+            ChangeBus::wants_rows(),
+        )
+        .await?;
+        scope.finish(change).await?;
+
         Ok(DatabaseResponse::Status {
             status: "Insert successful.".to_string(),
         })
@@ -73,11 +101,14 @@ impl DbHandler for DatabaseHandler {
             .db_request
             .values
             .as_ref()
-            .ok_or_else(|| anyhow!("Missing values for insert"))?;
+            .ok_or_else(|| ApiError::validation("Missing values for insert"))?;
         let table_name = &self.db_request.table;
-        let pool = &self.pool;
 
-        Insert::insert(pool, table_name, values).await?;
+        let mut scope = Scope::begin(&self.pool, self.transaction.as_ref(), "Insert").await?;
+        let change =
+            Insert::insert(scope.conn(), table_name, values, ChangeBus::wants_rows()).await?;
+        scope.finish(change).await?;
+
         Ok(DatabaseResponse::Status {
             status: "Insert successful.".to_string(),
         })
@@ -87,10 +118,18 @@ impl DbHandler for DatabaseHandler {
         delete_action: DeleteAction,
     ) -> Result<DatabaseResponse<serde_json::Value>> {
         let table_name = &self.db_request.table;
-        let pool = &self.pool;
         let filters = self.db_request.filters.clone();
 
-        Delete::delete(pool, table_name, delete_action, filters).await?;
+        let mut scope = Scope::begin(&self.pool, self.transaction.as_ref(), "Delete").await?;
+        let change = Delete::delete(
+            scope.conn(),
+            table_name,
+            delete_action,
+            filters,
+            ChangeBus::wants_rows(),
+        )
+        .await?;
+        scope.finish(change).await?;
 
         println!("deleting..");
         // Implement your deletion logic here, returning an appropriate DatabaseResponse.
@@ -105,12 +144,20 @@ impl DbHandler for DatabaseHandler {
             .db_request
             .values
             .as_ref()
-            .ok_or_else(|| anyhow!("Missing values for update"))?;
+            .ok_or_else(|| ApiError::validation("Missing values for update"))?;
         let table_name = &self.db_request.table;
-        let pool = &self.pool;
         let filters = self.db_request.filters.clone();
 
-        Update::update(pool, table_name, values.clone(), filters).await?;
+        let mut scope = Scope::begin(&self.pool, self.transaction.as_ref(), "Update").await?;
+        let change = Update::update(
+            scope.conn(),
+            table_name,
+            values.clone(),
+            filters,
+            ChangeBus::wants_rows(),
+        )
+        .await?;
+        scope.finish(change).await?;
         Ok(DatabaseResponse::Status {
             status: "Update successful".to_string(),
         })
@@ -122,8 +169,29 @@ impl DbHandler for DatabaseHandler {
         let table_name = &self.db_request.table;
         let pool = &self.pool;
 
-        let vals: Vec<serde_json::Value> =
-            Retrieve::retrieve(pool, table_name, self.db_request.clone().filters).await?;
+        let vals: Vec<serde_json::Value> = match &self.transaction {
+            // the cache doesn't know about writes the transaction did not commit yet
+            Some(transaction) => {
+                let mut scope = Scope::begin(pool, Some(transaction), "Retrieve").await?;
+                let rows = Retrieve::retrieve_in(
+                    scope.conn(),
+                    table_name,
+                    self.db_request.clone().filters,
+                )
+                .await?;
+                scope.finish_all(Vec::new()).await?;
+                rows
+            }
+            None => {
+                Retrieve::retrieve(
+                    pool,
+                    table_name,
+                    self.db_request.clone().filters,
+                    self.cache_mode,
+                )
+                .await?
+            }
+        };
 
         println!("vals: {:?}", vals);
 
@@ -2,29 +2,39 @@ use acid4sigmas_models::models::db::{
     BuildQuery, DatabaseAction, DeleteAction, Filters, QueryBuilder,
 };
 use serde_json::Value;
-use sqlx::PgPool;
+use sqlx::PgConnection;
+
+use super::changes::{ChangeOp, PendingChange};
+use super::Database;
+use crate::error::{ApiError, ErrorCode};
 
 pub struct Delete;
 
 impl Delete {
+    /// Runs the delete on `conn` without committing, the returned change has to
+    /// be applied once the surrounding transaction commits.
+    ///
+    /// The change only carries the written rows if `returning` is set.
     pub async fn delete(
-        pool: &PgPool,
+        conn: &mut PgConnection,
         table_name: &str,
         delete_action: DeleteAction,
         filters: Option<Filters>,
-    ) -> anyhow::Result<()> {
+        returning: bool,
+    ) -> anyhow::Result<PendingChange> {
         let query_builder: BuildQuery = QueryBuilder::from(QueryBuilder {
             table: table_name.to_string(),
             action: DatabaseAction::Delete(delete_action),
             filters,
             ..Default::default()
         })
-        .build_query()?;
+        .build_query()
+        .map_err(|e| ApiError::validation(e.to_string()))?;
 
         println!("{:?}", query_builder);
         let (query, params) = query_builder;
+        let query = Database::returning_all(query, returning);
 
-        let mut txn = pool.begin().await?;
         let mut query_builder = sqlx::query::<sqlx::Postgres>(&query);
 
         for value in params {
@@ -34,17 +44,27 @@ impl Delete {
                     if let Some(num) = n.as_i64() {
                         query_builder.bind(num)
                     } else {
-                        return Err(anyhow::anyhow!("Invalid number type for binding"));
+                        return Err(ApiError::new(
+                            ErrorCode::TypeMismatch,
+                            "Invalid number type for binding",
+                        )
+                        .into());
                     }
                 }
                 Value::Bool(b) => query_builder.bind(b),
-                _ => return Err(anyhow::anyhow!("Unsupported value type")),
+                _ => {
+                    return Err(
+                        ApiError::new(ErrorCode::TypeMismatch, "Unsupported value type").into(),
+                    )
+                }
             }
         }
 
-        query_builder.execute(&mut *txn).await?;
-        txn.commit().await?;
-
-        Ok(())
+        let rows = query_builder.fetch_all(&mut *conn).await?;
+        Ok(PendingChange::new(
+            table_name,
+            ChangeOp::Delete,
+            returning.then_some(&rows[..]),
+        ))
     }
 }
@@ -1,17 +1,28 @@
 use acid4sigmas_models::models::db::{BuildQuery, DatabaseAction, QueryBuilder, Values};
 use anyhow::Result;
 use serde_json::Value;
-use sqlx::PgPool;
+use sqlx::PgConnection;
 
-use crate::cache::{CacheKey, CACHE_MANAGER};
+use super::changes::{ChangeOp, PendingChange};
+use super::Database;
+use crate::error::{ApiError, ErrorCode};
 
 use super::table::Table;
 
 pub struct Insert;
 
 impl Insert {
-    pub async fn insert(pool: &PgPool, table_name: &str, values: &Values) -> Result<()> {
-        let table_columns = Table::get_table_columns_and_types(&pool, &table_name).await?;
+    /// Runs the insert on `conn` without committing, the returned change has to
+    /// be applied once the surrounding transaction commits.
+    ///
+    /// The change only carries the written rows if `returning` is set.
+    pub async fn insert(
+        conn: &mut PgConnection,
+        table_name: &str,
+        values: &Values,
+        returning: bool,
+    ) -> Result<PendingChange> {
+        let table_columns = Table::get_table_columns_and_types(&mut *conn, table_name).await?;
 
         let query_builder: BuildQuery = QueryBuilder::from(QueryBuilder {
             table: table_name.to_string(),
@@ -20,11 +31,12 @@ impl Insert {
             table_columns: Some(table_columns),
             ..Default::default()
         })
-        .build_query()?;
+        .build_query()
+        .map_err(|e| ApiError::validation(e.to_string()))?;
 
         let (query, params) = query_builder;
+        let query = Database::returning_all(query, returning);
 
-        let mut txn = pool.begin().await?;
         let mut query_builder = sqlx::query::<sqlx::Postgres>(&query);
 
         for value in params {
@@ -34,21 +46,27 @@ impl Insert {
                     if let Some(num) = n.as_i64() {
                         query_builder.bind(num)
                     } else {
-                        return Err(anyhow::anyhow!("Invalid number type for binding"));
+                        return Err(ApiError::new(
+                            ErrorCode::TypeMismatch,
+                            "Invalid number type for binding",
+                        )
+                        .into());
                     }
                 }
                 Value::Bool(b) => query_builder.bind(b),
-                _ => return Err(anyhow::anyhow!("Unsupported value type")),
+                _ => {
+                    return Err(
+                        ApiError::new(ErrorCode::TypeMismatch, "Unsupported value type").into(),
+                    )
+                }
             };
         }
 
-        query_builder.execute(&mut *txn).await?;
-        txn.commit().await?;
-
-        let cache_key_table = CacheKey::generate_table_cache_hash(&table_name);
-
-        CACHE_MANAGER.remove_by_prefix(&cache_key_table);
-
-        Ok(())
+        let rows = query_builder.fetch_all(&mut *conn).await?;
+        Ok(PendingChange::new(
+            table_name,
+            ChangeOp::Insert,
+            returning.then_some(&rows[..]),
+        ))
     }
 }
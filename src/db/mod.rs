@@ -1,16 +1,27 @@
+pub mod batch;
 pub mod bulk_insert;
+pub mod changes;
 pub mod db_handler;
 pub mod delete;
 pub mod insert;
+pub mod notify;
 pub mod retrieve;
 pub mod table;
+pub mod transaction;
 pub mod update;
+pub mod upsert;
 
 use acid4sigmas_models::secrets::{DB_NAME, DB_PORT, DB_PW};
 use anyhow::{Context, Result};
 use sqlx::PgPool;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use tokio::sync::OnceCell;
+
+use table::Table;
+
+/// the pool every caller of `Database::get_pool` shares
+static POOL: OnceCell<PgPool> = OnceCell::const_new();
 
 pub struct Database {
     pub pool: PgPool,
@@ -29,8 +40,14 @@ impl Database {
         Ok(Self { pool })
     }
 
+    /// Returns the pool of the process, connecting it on the first call.
+    /// Cloning a pool is cheap, the clones share its connections.
     pub async fn get_pool() -> Result<PgPool> {
-        Database::new().await.map(|db| db.pool)
+        let pool = POOL
+            .get_or_try_init(|| async { Database::new().await.map(|db| db.pool) })
+            .await?;
+
+        Ok(pool.clone())
     }
 
     pub async fn init(schema_path: PathBuf) -> Result<()> {
@@ -51,4 +68,88 @@ impl Database {
 
         Ok(())
     }
+
+    /// Makes a write statement return the rows it wrote if `returning`, for
+    /// the result of batches and for subscriptions.
+    pub fn returning_all(query: String, returning: bool) -> String {
+        if !returning {
+            return query;
+        }
+
+        format!("{} RETURNING *", query.trim_end().trim_end_matches(';'))
+    }
+
+    /// Returns the names of the tables created by the schema file.
+    pub fn schema_tables(schema_path: &Path) -> Result<Vec<String>> {
+        let schema = fs::read_to_string(schema_path)
+            .with_context(|| format!("Failed to read schema file from {:?}", schema_path))?;
+
+        let mut tables = Vec::new();
+
+        for statement in schema.split(";") {
+            let words: Vec<&str> = statement.split_whitespace().collect();
+
+            let name = match words.as_slice() {
+                [create, table, if_, not, exists, name, ..]
+                    if create.eq_ignore_ascii_case("create")
+                        && table.eq_ignore_ascii_case("table")
+                        && if_.eq_ignore_ascii_case("if")
+                        && not.eq_ignore_ascii_case("not")
+                        && exists.eq_ignore_ascii_case("exists") =>
+                {
+                    name
+                }
+                [create, table, name, ..]
+                    if create.eq_ignore_ascii_case("create")
+                        && table.eq_ignore_ascii_case("table") =>
+                {
+                    name
+                }
+                _ => continue,
+            };
+
+            // drop a column list written without a space, e.g. `users(`
+            let name = name.split('(').next().unwrap_or_default();
+
+            if Table::is_valid_name(name) {
+                tables.push(name.to_string());
+            }
+        }
+
+        Ok(tables)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_created_tables_and_skips_everything_else() {
+        let path = std::env::temp_dir().join(format!("a4s-schema-{}.sql", std::process::id()));
+        fs::write(
+            &path,
+            "
+            CREATE TABLE IF NOT EXISTS users (uid BIGINT PRIMARY KEY);
+            create table themes(uid BIGINT);
+            CREATE   TABLE\n  auth_tokens (jti TEXT);
+            CREATE INDEX users_email ON users (email);
+            INSERT INTO users (uid) VALUES (1);
+            CREATE TABLE \"quoted\" (uid BIGINT);
+            CREATE TABLE IF NOT EXISTS public.users (uid BIGINT);
+            ",
+        )
+        .unwrap();
+
+        let tables = Database::schema_tables(&path);
+        let _ = fs::remove_file(&path);
+
+        assert_eq!(tables.unwrap(), ["users", "themes", "auth_tokens"]);
+    }
+
+    #[test]
+    fn fails_without_schema_file() {
+        let path = std::env::temp_dir().join("a4s-schema-that-does-not-exist.sql");
+        assert!(Database::schema_tables(&path).is_err());
+    }
 }
@@ -0,0 +1,140 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use sqlx::postgres::PgListener;
+use sqlx::PgPool;
+use tokio::time::sleep;
+
+use super::changes::{Change, ChangeBus, ORIGIN_SETTING};
+use super::table::Table;
+use super::Database;
+use crate::cache::CACHE_MANAGER;
+use crate::config::NotifyConfig;
+use crate::instance;
+
+const TRIGGER_NAME: &str = "acid4sigmas_notify_change";
+
+/// Payload sent by the triggers on every write.
+#[derive(Debug, Deserialize)]
+struct ChangeNotification {
+    table: String,
+    /// instance that made the write, if it was made through this api
+    origin: Option<String>,
+}
+
+/// Invalidates the cache and resyncs live subscriptions on writes that did
+/// not go through this instance, using triggers on every table of the schema
+/// and a `LISTEN` connection.
+pub struct Notify;
+
+impl Notify {
+    /// Installs the triggers and listens for changes until the process exits,
+    /// reconnecting with backoff whenever the connection is lost.
+    pub async fn run(schema_path: PathBuf, notify_config: &NotifyConfig) {
+        let mut backoff = Duration::from_secs(1);
+
+        loop {
+            if let Err(e) = Self::listen(&schema_path, notify_config, &mut backoff).await {
+                eprintln!("error: change listener failed: {}", e);
+            }
+
+            sleep(backoff).await;
+            backoff = (backoff * 2).min(Duration::from_secs(notify_config.max_backoff_secs));
+        }
+    }
+
+    async fn listen(
+        schema_path: &Path,
+        notify_config: &NotifyConfig,
+        backoff: &mut Duration,
+    ) -> Result<()> {
+        let pool = Database::get_pool().await?;
+        let tables = Database::schema_tables(schema_path)?;
+
+        Self::install_triggers(&pool, &tables, &notify_config.channel).await?;
+
+        let mut listener = PgListener::connect_with(&pool).await?;
+        listener.listen(&notify_config.channel).await?;
+
+        // anything written before we were listening may be cached already
+        CACHE_MANAGER.flush().await;
+        ChangeBus::publish(Change::All);
+        *backoff = Duration::from_secs(1);
+
+        loop {
+            match listener.try_recv().await? {
+                Some(notification) => {
+                    match serde_json::from_str::<ChangeNotification>(notification.payload()) {
+                        // our own writes were applied when they were made
+                        Ok(change) if change.origin.as_deref() == Some(instance::id()) => {}
+                        Ok(change) => {
+                            CACHE_MANAGER.invalidate_table(&change.table).await;
+                            ChangeBus::publish(Change::Table {
+                                table: change.table,
+                            });
+                        }
+                        Err(e) => eprintln!("error: invalid change notification: {}", e),
+                    }
+                }
+                None => {
+                    // the connection was lost. reconnecting from scratch flushes
+                    // once we listen again, a flush now could be refilled with
+                    // rows whose changes are sent before then and never arrive
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    async fn install_triggers(pool: &PgPool, tables: &[String], channel: &str) -> Result<()> {
+        if !Table::is_valid_name(channel) {
+            return Err(anyhow!("Invalid notify channel name: {}", channel));
+        }
+
+        let function = format!(
+            r#"
+            CREATE OR REPLACE FUNCTION {TRIGGER_NAME}() RETURNS trigger AS $$
+            BEGIN
+                PERFORM pg_notify(
+                    '{channel}',
+                    json_build_object(
+                        'table', TG_TABLE_NAME,
+                        'op', TG_OP,
+                        'origin', current_setting('{ORIGIN_SETTING}', true)
+                    )::text
+                );
+                RETURN NULL;
+            END;
+            $$ LANGUAGE plpgsql
+            "#
+        );
+
+        sqlx::query(&function)
+            .execute(pool)
+            .await
+            .context("Failed to create the change notification function")?;
+
+        for table in tables {
+            let mut txn = pool.begin().await?;
+
+            sqlx::query(&format!("DROP TRIGGER IF EXISTS {TRIGGER_NAME} ON {table}"))
+                .execute(&mut *txn)
+                .await?;
+
+            sqlx::query(&format!(
+                "CREATE TRIGGER {TRIGGER_NAME} \
+                 AFTER INSERT OR UPDATE OR DELETE OR TRUNCATE ON {table} \
+                 FOR EACH STATEMENT EXECUTE FUNCTION {TRIGGER_NAME}()"
+            ))
+            .execute(&mut *txn)
+            .await
+            .with_context(|| format!("Failed to create change trigger on {}", table))?;
+
+            txn.commit().await?;
+        }
+
+        Ok(())
+    }
+}
@@ -1,12 +1,14 @@
-use crate::cache::{CacheKey, CACHE_MANAGER};
+use crate::cache::{CacheKey, CacheMode, CACHE_MANAGER, RETRIEVE_FLIGHTS};
+use crate::config;
+use crate::error::{ApiError, ErrorCode};
 use crate::timer::Timer;
-use acid4sigmas_models::db::{ModelRegistry, TableModel};
 use acid4sigmas_models::models::db::{BuildQuery, DatabaseAction, Filters, QueryBuilder};
-use anyhow::anyhow;
+use anyhow::Context;
 use sqlx::postgres::PgRow;
-use sqlx::PgPool;
+use sqlx::{PgConnection, PgExecutor, PgPool};
 
-use crate::MODEL_REGISTRY;
+use super::table::Table;
+use super::transaction::set_timeouts;
 
 pub struct Retrieve;
 
@@ -15,32 +17,112 @@ impl Retrieve {
         pool: &PgPool,
         table_name: &str,
         filters: Option<Filters>,
+        cache_mode: CacheMode,
     ) -> anyhow::Result<Vec<serde_json::Value>> {
         println!("filters: {:?}", filters);
 
         let timer = Timer::new();
 
+        let (query, params) = Self::build_query(table_name, filters)?;
+        let cache_key_gen = CacheKey::generate_cache_key(&table_name, &query, &params);
+
+        let cache_config = &config::get().cache;
+        let cacheable = cache_config.policy(table_name).enabled;
+
+        if cacheable && cache_mode.reads() {
+            if let Some(cache) = CACHE_MANAGER.get(&cache_key_gen) {
+                println!("value found in cache in {} µs", timer.elapsed_as_micros());
+
+                return Ok(cache);
+            } else {
+                println!("value not found in cache");
+            }
+        }
+
+        // read before querying, so a write that lands while the query runs
+        // keeps its result out of the cache
+        let generation = CACHE_MANAGER.generation(table_name);
+
+        if !cacheable || !cache_mode.reads() {
+            let models = Self::fetch_pooled(pool, table_name, &query, params).await?;
+
+            if cacheable && cache_mode.writes() {
+                let ttl = cache_config.ttl(table_name);
+                CACHE_MANAGER.insert(cache_key_gen, models.clone(), ttl, generation);
+            }
+
+            println!("finished in {} ms", timer.elapsed_as_millis());
+            return Ok(models);
+        }
+
+        // concurrent misses for the same query wait for the first one instead of
+        // all querying the database. a miss after an invalidation doesn't join a
+        // query that started before it, it could miss the write
+        let models = RETRIEVE_FLIGHTS
+            .run((cache_key_gen.clone(), generation), || async {
+                let models = Self::fetch_pooled(pool, table_name, &query, params).await?;
+
+                let ttl = cache_config.ttl(table_name);
+                CACHE_MANAGER.insert(cache_key_gen, models.clone(), ttl, generation);
+
+                Ok(models)
+            })
+            .await?;
+
+        println!("finished in {} ms", timer.elapsed_as_millis());
+        Ok(models)
+    }
+
+    /// Reads through `conn`, bypassing the cache. Used inside transactions,
+    /// which have to see their own uncommitted writes.
+    pub async fn retrieve_in(
+        conn: &mut PgConnection,
+        table_name: &str,
+        filters: Option<Filters>,
+    ) -> anyhow::Result<Vec<serde_json::Value>> {
+        let (query, params) = Self::build_query(table_name, filters)?;
+
+        Self::fetch(conn, table_name, &query, params).await
+    }
+
+    fn build_query(table_name: &str, filters: Option<Filters>) -> anyhow::Result<BuildQuery> {
         let query_builder: BuildQuery = QueryBuilder::from(QueryBuilder {
             table: table_name.to_string(),
             action: DatabaseAction::Retrieve,
             filters,
             ..Default::default()
         })
-        .build_query()?;
+        .build_query()
+        .map_err(|e| ApiError::validation(e.to_string()))?;
 
         println!("{:?}", query_builder);
-        let (query, params) = query_builder;
-        let cache_key_gen = CacheKey::generate_cache_key(&table_name, &query, &params);
+        Ok(query_builder)
+    }
 
-        if let Some(cache) = CACHE_MANAGER.get(&cache_key_gen) {
-            println!("value found in cache in {} µs", timer.elapsed_as_micros());
+    /// Fetches in a transaction of its own, so the query is bound by the
+    /// timeouts of retrieves.
+    async fn fetch_pooled(
+        pool: &PgPool,
+        table_name: &str,
+        query: &str,
+        params: Vec<serde_json::Value>,
+    ) -> anyhow::Result<Vec<serde_json::Value>> {
+        let mut txn = pool.begin().await?;
+        set_timeouts(&mut *txn, "Retrieve").await?;
 
-            return Ok(cache);
-        } else {
-            println!("value not found in cache");
-        }
+        let models = Self::fetch(&mut *txn, table_name, query, params).await?;
+        txn.commit().await?;
+
+        Ok(models)
+    }
 
-        let mut query_builder = sqlx::query(&query);
+    async fn fetch<'e>(
+        executor: impl PgExecutor<'e>,
+        table_name: &str,
+        query: &str,
+        params: Vec<serde_json::Value>,
+    ) -> anyhow::Result<Vec<serde_json::Value>> {
+        let mut query_builder = sqlx::query(query);
 
         for param in params {
             match param {
@@ -57,34 +139,21 @@ impl Retrieve {
                 serde_json::Value::Bool(b) => {
                     query_builder = query_builder.bind(b);
                 }
-                _ => return Err(anyhow!("Unsupported JSON type for parameter binding")),
+                _ => {
+                    return Err(ApiError::new(
+                        ErrorCode::TypeMismatch,
+                        "Unsupported JSON type for parameter binding",
+                    )
+                    .into())
+                }
             }
         }
 
         let rows: Vec<PgRow> = query_builder
-            .fetch_all(pool)
+            .fetch_all(executor)
             .await
-            .map_err(|e| anyhow!("Failed to fetch data: {}", e))?;
-
-        let registry: &ModelRegistry = MODEL_REGISTRY
-            .get()
-            .expect("Model registry not initialized");
-
-        if let Some(entry) = registry.get(table_name) {
-            let mut models: Vec<serde_json::Value> = Vec::new();
-
-            for row in rows {
-                println!("row");
-                let model_instance: Box<dyn TableModel + Send + Sync> = (entry.factory)(&row); // call the factory to create the model
-                models.push(model_instance.as_value());
-            }
-
-            CACHE_MANAGER.insert(cache_key_gen, models.clone());
-
-            println!("finished in {} ms", timer.elapsed_as_millis());
-            return Ok(models);
-        };
+            .context("Failed to fetch data")?;
 
-        Ok(Vec::new())
+        Ok(Table::models(table_name, &rows))
     }
 }
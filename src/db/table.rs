@@ -1,12 +1,24 @@
 use std::collections::HashMap;
 
+use acid4sigmas_models::db::{ModelRegistry, TableModel};
 use anyhow::Result;
-use sqlx::{PgPool, Row};
+use sqlx::postgres::PgRow;
+use sqlx::{PgExecutor, Row};
+
+use crate::MODEL_REGISTRY;
 
 pub struct Table;
 
 impl Table {
-    pub async fn exists(pool: &PgPool, table_name: &str) -> Result<bool> {
+    /// Whether `name` can be put into a statement as an identifier without quoting.
+    pub fn is_valid_name(name: &str) -> bool {
+        let mut chars = name.chars();
+
+        matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+            && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+    }
+
+    pub async fn exists<'e>(executor: impl PgExecutor<'e>, table_name: &str) -> Result<bool> {
         let query = r#"
             SELECT EXISTS (
                 SELECT 1
@@ -18,14 +30,39 @@ impl Table {
 
         let exists: (bool,) = sqlx::query_as(query)
             .bind(table_name)
-            .fetch_one(pool)
+            .fetch_one(executor)
             .await?;
 
         Ok(exists.0)
     }
 
-    pub async fn get_table_columns_and_types(
-        pool: &PgPool,
+    /// Columns of the primary key of `table_name`, empty if it has none.
+    pub async fn primary_key<'e>(
+        executor: impl PgExecutor<'e>,
+        table_name: &str,
+    ) -> Result<Vec<String>> {
+        let query = r#"
+            SELECT kcu.column_name
+            FROM information_schema.table_constraints tc
+            JOIN information_schema.key_column_usage kcu
+                ON kcu.constraint_name = tc.constraint_name
+                AND kcu.table_schema = tc.table_schema
+            WHERE tc.table_schema = 'public'
+            AND tc.table_name = $1
+            AND tc.constraint_type = 'PRIMARY KEY'
+            ORDER BY kcu.ordinal_position
+        "#;
+
+        let columns = sqlx::query_scalar(query)
+            .bind(table_name)
+            .fetch_all(executor)
+            .await?;
+
+        Ok(columns)
+    }
+
+    pub async fn get_table_columns_and_types<'e>(
+        executor: impl PgExecutor<'e>,
         table_name: &str,
     ) -> Result<HashMap<String, String>> {
         let query = r#"
@@ -34,7 +71,10 @@ impl Table {
             WHERE table_name = $1
         "#;
 
-        let rows = sqlx::query(query).bind(table_name).fetch_all(pool).await?;
+        let rows = sqlx::query(query)
+            .bind(table_name)
+            .fetch_all(executor)
+            .await?;
 
         let mut columns_and_types = HashMap::new();
 
@@ -46,4 +86,31 @@ impl Table {
 
         Ok(columns_and_types)
     }
+
+    /// Whether a model is registered for `table_name`, rows of tables without
+    /// one can't be turned into json.
+    pub fn has_model(table_name: &str) -> bool {
+        MODEL_REGISTRY
+            .get()
+            .is_some_and(|registry| registry.get(table_name).is_some())
+    }
+
+    /// Turns rows of `table_name` into json through the model registered for
+    /// the table, tables without a model have no rows to show.
+    pub fn models(table_name: &str, rows: &[PgRow]) -> Vec<serde_json::Value> {
+        let registry: &ModelRegistry = MODEL_REGISTRY
+            .get()
+            .expect("Model registry not initialized");
+
+        let Some(entry) = registry.get(table_name) else {
+            return Vec::new();
+        };
+
+        rows.iter()
+            .map(|row| {
+                let model_instance: Box<dyn TableModel + Send + Sync> = (entry.factory)(row); // call the factory to create the model
+                model_instance.as_value()
+            })
+            .collect()
+    }
 }
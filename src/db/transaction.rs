@@ -0,0 +1,182 @@
+use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use sqlx::{PgConnection, PgPool, Postgres, Transaction};
+use tokio::sync::{MappedMutexGuard, Mutex, MutexGuard};
+
+use super::changes::{ChangeBus, PendingChange};
+use crate::config;
+use crate::error::ApiError;
+
+/// A transaction opened with `Begin`, shared by the requests of its session.
+///
+/// `None` once it was committed or rolled back.
+pub type SharedTransaction = Arc<Mutex<Option<SessionTransaction>>>;
+
+/// A transaction that spans several requests of a session.
+pub struct SessionTransaction {
+    txn: Transaction<'static, Postgres>,
+    /// writes made so far, applied once the transaction commits
+    changes: Vec<PendingChange>,
+    last_used: Instant,
+    /// a request failed, postgres aborted the transaction and would silently
+    /// roll back a `COMMIT`
+    failed: bool,
+}
+
+impl SessionTransaction {
+    pub async fn begin(pool: &PgPool) -> Result<Self> {
+        let mut txn = pool.begin().await?;
+        ChangeBus::tag_transaction(&mut *txn).await?;
+
+        Ok(Self {
+            txn,
+            changes: Vec::new(),
+            last_used: Instant::now(),
+            failed: false,
+        })
+    }
+
+    /// Commits the transaction, or rolls it back and fails if one of its
+    /// requests failed.
+    pub async fn commit(self) -> Result<()> {
+        if self.failed {
+            self.txn.rollback().await?;
+            return Err(ApiError::validation(
+                "A request of the transaction failed, it was rolled back.",
+            )
+            .into());
+        }
+
+        self.txn.commit().await?;
+
+        for change in self.changes {
+            change.apply().await;
+        }
+
+        Ok(())
+    }
+
+    pub async fn rollback(self) -> Result<()> {
+        self.txn.rollback().await?;
+        Ok(())
+    }
+
+    /// How long no request used the transaction.
+    pub fn idle(&self) -> Duration {
+        self.last_used.elapsed()
+    }
+}
+
+/// Where a single request runs its statements: in a transaction of its own,
+/// or in the transaction of its session.
+pub enum Scope<'a> {
+    Own(Transaction<'static, Postgres>),
+    Session(InUse<'a>),
+}
+
+/// The session's transaction while a request runs in it. The transaction
+/// counts as used until the request ends, however it ends.
+pub struct InUse<'a>(MappedMutexGuard<'a, SessionTransaction>);
+
+impl Deref for InUse<'_> {
+    type Target = SessionTransaction;
+
+    fn deref(&self) -> &SessionTransaction {
+        &self.0
+    }
+}
+
+impl DerefMut for InUse<'_> {
+    fn deref_mut(&mut self) -> &mut SessionTransaction {
+        &mut self.0
+    }
+}
+
+impl Drop for InUse<'_> {
+    fn drop(&mut self) {
+        self.0.last_used = Instant::now();
+    }
+}
+
+impl<'a> Scope<'a> {
+    /// Starts a request running `action`, its statements are bound by the
+    /// timeouts of that action.
+    pub async fn begin(
+        pool: &PgPool,
+        transaction: Option<&'a SharedTransaction>,
+        action: &str,
+    ) -> Result<Self> {
+        let Some(transaction) = transaction else {
+            let mut txn = pool.begin().await?;
+            ChangeBus::tag_transaction(&mut *txn).await?;
+            set_timeouts(&mut *txn, action).await?;
+
+            return Ok(Scope::Own(txn));
+        };
+
+        match MutexGuard::try_map(transaction.lock().await, Option::as_mut) {
+            Ok(transaction) if transaction.failed => Err(ApiError::validation(
+                "A request of the transaction failed, roll it back.",
+            )
+            .into()),
+            Ok(mut transaction) => {
+                // until the request finishes, so a request that fails or is
+                // cancelled half way leaves the transaction failed
+                transaction.failed = true;
+                set_timeouts(&mut *transaction.txn, action).await?;
+                Ok(Scope::Session(InUse(transaction)))
+            }
+            Err(_) => Err(ApiError::validation("The transaction already ended.").into()),
+        }
+    }
+
+    pub fn conn(&mut self) -> &mut PgConnection {
+        match self {
+            Scope::Own(txn) => &mut **txn,
+            Scope::Session(transaction) => &mut *transaction.txn,
+        }
+    }
+
+    /// Ends the request. Its own transaction is committed right away, a change
+    /// made in the session's transaction waits for that to commit.
+    pub async fn finish(self, change: PendingChange) -> Result<()> {
+        self.finish_all(vec![change]).await
+    }
+
+    pub async fn finish_all(self, changes: Vec<PendingChange>) -> Result<()> {
+        match self {
+            Scope::Own(txn) => {
+                txn.commit().await?;
+
+                for change in changes {
+                    change.apply().await;
+                }
+            }
+            Scope::Session(mut transaction) => {
+                transaction.failed = false;
+                transaction.changes.extend(changes);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Limits how long the statements of the current transaction may run and wait
+/// for locks, with the timeouts `[query]` of `Config.toml` sets for `action`.
+pub async fn set_timeouts(conn: &mut PgConnection, action: &str) -> Result<()> {
+    let (statement_timeout, lock_timeout) = config::get().query.timeouts(action);
+
+    sqlx::query(
+        "SELECT set_config('statement_timeout', $1, true), set_config('lock_timeout', $2, true)",
+    )
+    .bind(statement_timeout.to_string())
+    .bind(lock_timeout.to_string())
+    .execute(conn)
+    .await?;
+
+    Ok(())
+}
@@ -1,21 +1,28 @@
 use super::table::Table;
 use acid4sigmas_models::models::db::{BuildQuery, DatabaseAction, Filters, QueryBuilder};
 use serde_json::Value;
-use sqlx::PgPool;
+use sqlx::PgConnection;
 use std::collections::HashMap;
 
-use crate::cache::{CacheKey, CACHE_MANAGER};
+use super::changes::{ChangeOp, PendingChange};
+use super::Database;
+use crate::error::{ApiError, ErrorCode};
 
 pub struct Update;
 
 impl Update {
+    /// Runs the update on `conn` without committing, the returned change has to
+    /// be applied once the surrounding transaction commits.
+    ///
+    /// The change only carries the written rows if `returning` is set.
     pub async fn update(
-        pool: &PgPool,
+        conn: &mut PgConnection,
         table_name: &str,
         values: HashMap<String, Value>,
         filters: Option<Filters>,
-    ) -> anyhow::Result<()> {
-        let table_columns = Table::get_table_columns_and_types(&pool, &table_name).await?;
+        returning: bool,
+    ) -> anyhow::Result<PendingChange> {
+        let table_columns = Table::get_table_columns_and_types(&mut *conn, table_name).await?;
 
         let query_builder: BuildQuery = QueryBuilder::from(QueryBuilder {
             table: table_name.to_string(),
@@ -25,13 +32,14 @@ impl Update {
             filters,
             ..Default::default()
         })
-        .build_query()?;
+        .build_query()
+        .map_err(|e| ApiError::validation(e.to_string()))?;
 
         println!("{:?}", query_builder);
 
         let (query, params) = query_builder;
+        let query = Database::returning_all(query, returning);
 
-        let mut txn = pool.begin().await?;
         let mut query_builder = sqlx::query::<sqlx::Postgres>(&query);
 
         for value in params {
@@ -41,21 +49,27 @@ impl Update {
                     if let Some(num) = n.as_i64() {
                         query_builder.bind(num)
                     } else {
-                        return Err(anyhow::anyhow!("Invalid number type for binding"));
+                        return Err(ApiError::new(
+                            ErrorCode::TypeMismatch,
+                            "Invalid number type for binding",
+                        )
+                        .into());
                     }
                 }
                 Value::Bool(b) => query_builder.bind(b),
-                _ => return Err(anyhow::anyhow!("Unsupported value type")),
+                _ => {
+                    return Err(
+                        ApiError::new(ErrorCode::TypeMismatch, "Unsupported value type").into(),
+                    )
+                }
             }
         }
 
-        query_builder.execute(&mut *txn).await?;
-        txn.commit().await?;
-
-        let cache_key_table = CacheKey::generate_table_cache_hash(&table_name);
-
-        CACHE_MANAGER.remove_by_prefix(&cache_key_table);
-
-        Ok(())
+        let rows = query_builder.fetch_all(&mut *conn).await?;
+        Ok(PendingChange::new(
+            table_name,
+            ChangeOp::Update,
+            returning.then_some(&rows[..]),
+        ))
     }
 }
@@ -0,0 +1,128 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use serde_json::Value;
+use sqlx::PgConnection;
+
+use super::changes::{ChangeOp, PendingChange};
+use super::table::Table;
+use crate::error::{ApiError, ErrorCode};
+
+pub struct Upsert;
+
+impl Upsert {
+    /// Inserts a row, or updates the row it conflicts with on the `conflict`
+    /// columns. Runs on `conn` without committing, the returned change has to
+    /// be applied once the surrounding transaction commits.
+    pub async fn upsert(
+        conn: &mut PgConnection,
+        table_name: &str,
+        values: &HashMap<String, Value>,
+        conflict: &[String],
+    ) -> Result<PendingChange> {
+        if !Table::is_valid_name(table_name) {
+            return Err(ApiError::new(ErrorCode::UnknownTable, "No such table exists.").into());
+        }
+
+        let table_columns = Table::get_table_columns_and_types(&mut *conn, table_name).await?;
+
+        if values.is_empty() {
+            return Err(ApiError::validation("Missing values for upsert").into());
+        }
+
+        if conflict.is_empty() {
+            return Err(ApiError::validation("Missing conflict columns for upsert").into());
+        }
+
+        for column in values.keys().chain(conflict) {
+            if !table_columns.contains_key(column) || !Table::is_valid_name(column) {
+                return Err(
+                    ApiError::new(ErrorCode::UnknownColumn, "No such column exists.")
+                        .with_column(column.as_str())
+                        .into(),
+                );
+            }
+        }
+
+        let columns: Vec<&String> = values.keys().collect();
+
+        let placeholders: Vec<String> = columns
+            .iter()
+            .enumerate()
+            .map(|(i, column)| {
+                let data_type = &table_columns[column.as_str()];
+
+                // parameters are bound as text, numbers and booleans, so they are
+                // cast to the type of their column. arrays and user defined types
+                // have no usable name in information_schema
+                if data_type != "ARRAY"
+                    && data_type
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == ' ')
+                {
+                    format!("${}::{}", i + 1, data_type)
+                } else {
+                    format!("${}", i + 1)
+                }
+            })
+            .collect();
+
+        let updates: Vec<String> = columns
+            .iter()
+            .map(|column| format!("{column} = EXCLUDED.{column}"))
+            .collect();
+
+        let column_list = columns
+            .iter()
+            .map(|c| c.as_str())
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        let query = format!(
+            "INSERT INTO {} ({}) VALUES ({}) ON CONFLICT ({}) DO UPDATE SET {} RETURNING *",
+            table_name,
+            column_list,
+            placeholders.join(", "),
+            conflict.join(", "),
+            updates.join(", ")
+        );
+
+        let mut query_builder = sqlx::query::<sqlx::Postgres>(&query);
+
+        for column in columns {
+            query_builder = match &values[column.as_str()] {
+                Value::String(s) => query_builder.bind(s.clone()),
+                Value::Number(n) => {
+                    if let Some(num) = n.as_i64() {
+                        query_builder.bind(num)
+                    } else if let Some(num) = n.as_f64() {
+                        query_builder.bind(num)
+                    } else {
+                        return Err(ApiError::new(
+                            ErrorCode::TypeMismatch,
+                            "Invalid number type for binding",
+                        )
+                        .with_column(column.as_str())
+                        .into());
+                    }
+                }
+                Value::Bool(b) => query_builder.bind(*b),
+                Value::Null => query_builder.bind(None::<String>),
+                _ => {
+                    return Err(
+                        ApiError::new(ErrorCode::TypeMismatch, "Unsupported value type")
+                            .with_column(column.as_str())
+                            .into(),
+                    )
+                }
+            };
+        }
+
+        let rows = query_builder.fetch_all(&mut *conn).await?;
+        Ok(PendingChange::new(
+            table_name,
+            ChangeOp::Upsert,
+            Some(&rows[..]),
+        ))
+    }
+}
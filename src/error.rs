@@ -0,0 +1,317 @@
+use std::fmt;
+use std::time::Duration;
+
+use acid4sigmas_models::error_response;
+use actix_web::HttpResponse;
+use serde::Serialize;
+use sqlx::error::DatabaseError;
+use sqlx::postgres::PgDatabaseError;
+
+/// Machine readable category of an error sent to a client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    Validation,
+    UnknownTable,
+    UnknownColumn,
+    TypeMismatch,
+    UniqueViolation,
+    ForeignKeyViolation,
+    NotNullViolation,
+    Auth,
+    Forbidden,
+    RateLimit,
+    Timeout,
+    Internal,
+}
+
+/// Error as it is sent to clients.
+///
+/// Database errors are mapped onto an [`ErrorCode`] through their SQLSTATE,
+/// anything that can't be mapped becomes `internal` without exposing the
+/// underlying message.
+#[derive(Debug, Clone, Serialize)]
+pub struct ApiError {
+    pub code: ErrorCode,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub column: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub constraint: Option<String>,
+    /// index of the failing step of a batch
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub step: Option<usize>,
+    /// seconds to wait before retrying a rate limited request
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry_after: Option<u64>,
+}
+
+impl ApiError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            column: None,
+            constraint: None,
+            step: None,
+            retry_after: None,
+        }
+    }
+
+    pub fn validation(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::Validation, message)
+    }
+
+    pub fn internal() -> Self {
+        Self::new(ErrorCode::Internal, "Internal server error.")
+    }
+
+    pub fn with_column(mut self, column: impl Into<String>) -> Self {
+        self.column = Some(column.into());
+        self
+    }
+
+    pub fn with_constraint(mut self, constraint: impl Into<String>) -> Self {
+        self.constraint = Some(constraint.into());
+        self
+    }
+
+    pub fn with_step(mut self, step: usize) -> Self {
+        self.step = Some(step);
+        self
+    }
+
+    /// Rounded up to whole seconds, so a client waiting that long fits in.
+    pub fn with_retry_after(mut self, retry_after: Duration) -> Self {
+        let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+        self.retry_after = Some(secs);
+        self
+    }
+
+    /// Turns the error into the response of a plain http request.
+    pub fn to_http_response(&self) -> HttpResponse {
+        match self.code {
+            ErrorCode::Internal => error_response!(500, self.message.clone()),
+            ErrorCode::Validation => error_response!(400, self.message.clone()),
+            ErrorCode::Auth | ErrorCode::Forbidden => error_response!(403, self.message.clone()),
+            ErrorCode::RateLimit => error_response!(429, self.message.clone()),
+            _ => error_response!(400, self.message.clone()),
+        }
+    }
+
+    fn from_database_error(db_error: &(dyn DatabaseError + 'static)) -> Self {
+        let pg_error = db_error.try_downcast_ref::<PgDatabaseError>();
+        let code = db_error.code().unwrap_or_default();
+
+        let (code, message) = match code.as_ref() {
+            "23505" => (
+                ErrorCode::UniqueViolation,
+                "A row with the same unique value already exists.",
+            ),
+            "23503" => (
+                ErrorCode::ForeignKeyViolation,
+                "The row references a row that does not exist, or is still referenced.",
+            ),
+            "23502" => (ErrorCode::NotNullViolation, "A required value is missing."),
+            "23514" => (
+                ErrorCode::Validation,
+                "A value violates a check constraint.",
+            ),
+            "25P02" => (
+                ErrorCode::Validation,
+                "The transaction was aborted by an earlier error, roll it back.",
+            ),
+            "57014" => (ErrorCode::Timeout, "The query took too long."),
+            "55P03" => (ErrorCode::Timeout, "Timed out waiting for a lock."),
+            "42P01" => (ErrorCode::UnknownTable, "No such table exists."),
+            "42703" => (ErrorCode::UnknownColumn, "No such column exists."),
+            // invalid text representation, datatype mismatch, numeric out of range,
+            // invalid datetime and operators that don't exist for the given types
+            "22P02" | "42804" | "22003" | "22007" | "22008" | "42883" => (
+                ErrorCode::TypeMismatch,
+                "A value does not match the type of its column.",
+            ),
+            _ => return Self::internal(),
+        };
+
+        let mut error = Self::new(code, message);
+
+        let column = pg_error
+            .and_then(|e| e.column())
+            .map(str::to_string)
+            .or_else(|| quoted_column(db_error.message()));
+        if let Some(column) = column {
+            error = error.with_column(column);
+        }
+
+        if let Some(constraint) = db_error.constraint() {
+            error = error.with_constraint(constraint);
+        }
+
+        error
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+impl From<&anyhow::Error> for ApiError {
+    fn from(error: &anyhow::Error) -> Self {
+        for cause in error.chain() {
+            if let Some(api_error) = cause.downcast_ref::<ApiError>() {
+                return api_error.clone();
+            }
+
+            if let Some(sqlx::Error::Database(db_error)) = cause.downcast_ref::<sqlx::Error>() {
+                let api_error = Self::from_database_error(db_error.as_ref());
+
+                if api_error.code == ErrorCode::Internal {
+                    eprintln!("error: {:?}", error);
+                }
+
+                return api_error;
+            }
+        }
+
+        eprintln!("error: {:?}", error);
+        Self::internal()
+    }
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(error: anyhow::Error) -> Self {
+        Self::from(&error)
+    }
+}
+
+/// Reads the column out of messages like `column "foo" does not exist`, for
+/// errors postgres does not attach the column to.
+fn quoted_column(message: &str) -> Option<String> {
+    let rest = message.strip_prefix("column \"")?;
+    let end = rest.find('"')?;
+    Some(rest[..end].to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+    use std::error::Error as StdError;
+
+    use sqlx::error::ErrorKind;
+
+    use super::*;
+
+    /// A database error as a driver other than postgres would report it.
+    #[derive(Debug)]
+    struct SqlState {
+        code: &'static str,
+        message: &'static str,
+        constraint: Option<&'static str>,
+    }
+
+    impl fmt::Display for SqlState {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str(self.message)
+        }
+    }
+
+    impl StdError for SqlState {}
+
+    impl DatabaseError for SqlState {
+        fn message(&self) -> &str {
+            self.message
+        }
+
+        fn code(&self) -> Option<Cow<'_, str>> {
+            Some(Cow::Borrowed(self.code))
+        }
+
+        fn as_error(&self) -> &(dyn StdError + Send + Sync + 'static) {
+            self
+        }
+
+        fn as_error_mut(&mut self) -> &mut (dyn StdError + Send + Sync + 'static) {
+            self
+        }
+
+        fn into_error(self: Box<Self>) -> Box<dyn StdError + Send + Sync + 'static> {
+            self
+        }
+
+        fn constraint(&self) -> Option<&str> {
+            self.constraint
+        }
+
+        fn kind(&self) -> ErrorKind {
+            ErrorKind::Other
+        }
+    }
+
+    fn api_error(code: &'static str, message: &'static str) -> ApiError {
+        let error = SqlState {
+            code,
+            message,
+            constraint: None,
+        };
+        ApiError::from(anyhow::Error::from(sqlx::Error::Database(Box::new(error))))
+    }
+
+    #[test]
+    fn maps_sqlstates_to_codes() {
+        for (sqlstate, code) in [
+            ("23505", ErrorCode::UniqueViolation),
+            ("23503", ErrorCode::ForeignKeyViolation),
+            ("23502", ErrorCode::NotNullViolation),
+            ("23514", ErrorCode::Validation),
+            ("25P02", ErrorCode::Validation),
+            ("57014", ErrorCode::Timeout),
+            ("55P03", ErrorCode::Timeout),
+            ("42P01", ErrorCode::UnknownTable),
+            ("42703", ErrorCode::UnknownColumn),
+            ("22P02", ErrorCode::TypeMismatch),
+            ("42883", ErrorCode::TypeMismatch),
+        ] {
+            assert_eq!(api_error(sqlstate, "failed").code, code, "{}", sqlstate);
+        }
+    }
+
+    #[test]
+    fn hides_the_message_of_unmapped_errors() {
+        let error = api_error("XX000", "secret internals of the database");
+
+        assert_eq!(error.code, ErrorCode::Internal);
+        assert_eq!(error.message, "Internal server error.");
+
+        let error = ApiError::from(anyhow::anyhow!("connection refused"));
+        assert_eq!(error.code, ErrorCode::Internal);
+        assert_eq!(error.message, "Internal server error.");
+    }
+
+    #[test]
+    fn keeps_column_and_constraint() {
+        let error = api_error("42703", "column \"nickname\" does not exist");
+        assert_eq!(error.column.as_deref(), Some("nickname"));
+
+        let unique = SqlState {
+            code: "23505",
+            message: "duplicate key value violates unique constraint",
+            constraint: Some("users_email_key"),
+        };
+        let error = ApiError::from(anyhow::Error::from(sqlx::Error::Database(Box::new(unique))));
+        assert_eq!(error.constraint.as_deref(), Some("users_email_key"));
+        assert_eq!(error.column, None);
+    }
+
+    #[test]
+    fn finds_api_errors_behind_context() {
+        let error = anyhow::Error::from(ApiError::new(ErrorCode::Forbidden, "no"))
+            .context("while running step 2");
+
+        assert_eq!(ApiError::from(&error).code, ErrorCode::Forbidden);
+    }
+}
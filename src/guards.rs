@@ -0,0 +1,256 @@
+use serde_json::{Map, Value};
+
+use crate::config::{self, QueryConfig, QueryLimits};
+use crate::error::ApiError;
+use crate::policy;
+
+/// Bounds what the raw request `request` may cost, with the limits `[query]`
+/// of `Config.toml` sets for its table.
+///
+/// Retrieves without a limit get the default one and may not ask for more
+/// than the maximum, bulk inserts may not write more than the maximum rows,
+/// and updates and deletes of tables that require it need a `where` filter.
+///
+/// Runs before the policy, a `where` filter it adds for the row rules of the
+/// token doesn't count as the client's.
+pub fn apply(request: &mut Value) -> Result<(), ApiError> {
+    apply_with(request, &config::get().query)
+}
+
+fn apply_with(request: &mut Value, query: &QueryConfig) -> Result<(), ApiError> {
+    let table = request
+        .get("table")
+        .and_then(Value::as_str)
+        .unwrap_or_default()
+        .to_string();
+    let limits = query.limits(&table);
+
+    match policy::action_name(request) {
+        "Retrieve" => limit_rows(request, &table, &limits),
+        "BulkInsert" => {
+            if limits.max_bulk_rows > 0 && rows_written(request) > limits.max_bulk_rows {
+                return Err(ApiError::validation(format!(
+                    "A bulk insert into {} can write at most {} rows.",
+                    table, limits.max_bulk_rows
+                )));
+            }
+
+            Ok(())
+        }
+        "Update" | "Delete" if limits.require_filters => {
+            let filtered = request
+                .get("filters")
+                .and_then(|filters| filters.get("where"))
+                .and_then(Value::as_object)
+                .is_some_and(|conditions| !conditions.is_empty());
+
+            if !filtered {
+                return Err(ApiError::validation(format!(
+                    "Updates and deletes of {} need a where filter.",
+                    table
+                )));
+            }
+
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Bounds the size of a batch, how many steps it has and how many rows they
+/// write together, with `[query]` of `Config.toml`.
+pub fn check_batch(steps: &[Value]) -> Result<(), ApiError> {
+    let query = &config::get().query;
+
+    if query.max_batch_steps > 0 && steps.len() > query.max_batch_steps {
+        return Err(ApiError::validation(format!(
+            "A batch can have at most {} steps.",
+            query.max_batch_steps
+        )));
+    }
+
+    let rows: usize = steps.iter().map(rows_written).sum();
+
+    if query.max_batch_rows > 0 && rows > query.max_batch_rows {
+        return Err(ApiError::validation(format!(
+            "A batch can write at most {} rows.",
+            query.max_batch_rows
+        )));
+    }
+
+    Ok(())
+}
+
+/// Rows a raw request inserts, any other request counts as one.
+fn rows_written(request: &Value) -> usize {
+    match request.get("bulk_values") {
+        Some(Value::Array(rows)) => rows.len(),
+        _ => 1,
+    }
+}
+
+fn limit_rows(request: &mut Value, table: &str, limits: &QueryLimits) -> Result<(), ApiError> {
+    let Some(request) = request.as_object_mut() else {
+        return Ok(());
+    };
+
+    let limit = request
+        .get("filters")
+        .and_then(|filters| filters.get("limit"))
+        .filter(|limit| !limit.is_null());
+
+    match limit {
+        Some(limit) => match limit.as_u64() {
+            Some(limit) if limits.max_limit > 0 && limit > limits.max_limit => {
+                Err(ApiError::validation(format!(
+                    "A retrieve from {} can return at most {} rows.",
+                    table, limits.max_limit
+                )))
+            }
+            Some(_) => Ok(()),
+            None => Err(ApiError::validation(
+                "The limit has to be a whole number of at least 0.",
+            )),
+        },
+        None if limits.default_limit > 0 => {
+            let filters = request.entry("filters").or_insert(Value::Null);
+            if !filters.is_object() {
+                *filters = Value::Object(Map::new());
+            }
+
+            filters["limit"] = Value::from(limits.default_limit);
+            Ok(())
+        }
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::error::ErrorCode;
+
+    const QUERY: &str = r#"
+        default_limit = 100
+        max_limit = 1000
+        max_bulk_rows = 2
+
+        [tables.users]
+        max_limit = 10
+        require_filters = true
+    "#;
+
+    #[test]
+    fn sets_the_default_limit() {
+        let query: QueryConfig = toml::from_str(QUERY).unwrap();
+
+        let mut request = json!({ "table": "themes", "action": "Retrieve" });
+        apply_with(&mut request, &query).unwrap();
+        assert_eq!(request["filters"]["limit"], 100);
+
+        let mut request = json!({
+            "table": "themes",
+            "action": "Subscribe",
+            "filters": { "where": { "uid": 1 }, "limit": null }
+        });
+        apply_with(&mut request, &query).unwrap();
+        assert_eq!(request["filters"]["limit"], 100);
+        assert_eq!(request["filters"]["where"]["uid"], 1);
+
+        let mut request = json!({
+            "table": "themes",
+            "action": "Retrieve",
+            "filters": { "limit": 5 }
+        });
+        apply_with(&mut request, &query).unwrap();
+        assert_eq!(request["filters"]["limit"], 5);
+    }
+
+    #[test]
+    fn rejects_limits_over_the_maximum() {
+        let query: QueryConfig = toml::from_str(QUERY).unwrap();
+
+        for (table, limit, allowed) in [
+            ("themes", 1000, true),
+            ("themes", 1001, false),
+            ("users", 10, true),
+            ("users", 11, false),
+        ] {
+            let mut request =
+                json!({ "table": table, "action": "Retrieve", "filters": { "limit": limit } });
+            let result = apply_with(&mut request, &query);
+
+            assert_eq!(result.is_ok(), allowed, "{} rows of {}", limit, table);
+            if let Err(error) = result {
+                assert_eq!(error.code, ErrorCode::Validation);
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_limits_that_are_no_row_count() {
+        let query: QueryConfig = toml::from_str(QUERY).unwrap();
+
+        for limit in [json!(-1), json!(2.5), json!("10")] {
+            let mut request =
+                json!({ "table": "themes", "action": "Retrieve", "filters": { "limit": limit } });
+            let error = apply_with(&mut request, &query).unwrap_err();
+
+            assert_eq!(error.code, ErrorCode::Validation, "limit {}", limit);
+        }
+    }
+
+    #[test]
+    fn rejects_bulk_inserts_over_the_maximum() {
+        let query: QueryConfig = toml::from_str(QUERY).unwrap();
+
+        for (rows, allowed) in [(2, true), (3, false)] {
+            let mut request = json!({
+                "table": "themes",
+                "action": "BulkInsert",
+                "bulk_values": vec![json!({ "uid": 1 }); rows]
+            });
+            assert_eq!(apply_with(&mut request, &query).is_ok(), allowed);
+        }
+    }
+
+    #[test]
+    fn requires_filters_where_configured() {
+        let query: QueryConfig = toml::from_str(QUERY).unwrap();
+
+        let mut unfiltered =
+            json!({ "table": "users", "action": "Update", "values": { "owner": true } });
+        assert!(apply_with(&mut unfiltered, &query).is_err());
+
+        let mut empty =
+            json!({ "table": "users", "action": { "Delete": null }, "filters": { "where": {} } });
+        assert!(apply_with(&mut empty, &query).is_err());
+
+        let mut filtered = json!({
+            "table": "users",
+            "action": "Update",
+            "values": { "owner": true },
+            "filters": { "where": { "uid": 1 } }
+        });
+        assert!(apply_with(&mut filtered, &query).is_ok());
+
+        let mut other_table =
+            json!({ "table": "themes", "action": "Update", "values": { "x": 1 } });
+        assert!(apply_with(&mut other_table, &query).is_ok());
+    }
+
+    #[test]
+    fn caps_batch_steps_and_rows() {
+        let query = &config::get().query;
+
+        assert!(check_batch(&vec![json!({}); query.max_batch_steps]).is_ok());
+        assert!(check_batch(&vec![json!({}); query.max_batch_steps + 1]).is_err());
+
+        let rows = vec![json!({}); query.max_batch_rows];
+        let bulk_insert = json!({ "action": "BulkInsert", "bulk_values": rows });
+        assert!(check_batch(&[bulk_insert.clone()]).is_ok());
+        assert!(check_batch(&[bulk_insert, json!({})]).is_err());
+    }
+}
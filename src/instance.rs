@@ -0,0 +1,17 @@
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+static INSTANCE_ID: OnceLock<String> = OnceLock::new();
+
+/// Identifies this process among all instances sharing the database or the
+/// redis channel, so each of them can skip the messages it sent itself.
+pub fn id() -> &'static str {
+    INSTANCE_ID.get_or_init(|| {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or_default();
+
+        format!("{}-{}", std::process::id(), nanos)
+    })
+}
@@ -0,0 +1,326 @@
+use std::collections::HashMap;
+use std::fs;
+use std::sync::OnceLock;
+
+use acid4sigmas_models::secrets::SECRET_KEY;
+use anyhow::{anyhow, Context, Result};
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+
+use crate::auth::TokenClaims;
+use crate::config::{JwtConfig, KeyConfig};
+use crate::error::{ApiError, ErrorCode};
+
+pub static KEYSET: OnceLock<Keyset> = OnceLock::new();
+
+struct Key {
+    algorithm: Algorithm,
+    decoding: DecodingKey,
+}
+
+/// The keys tokens are verified with, selected by the `kid` in the header of
+/// the token.
+///
+/// Several keys can be active at once, so a new key can be rolled out before
+/// the tokens signed with the old one expire. Tokens without a `kid` are
+/// verified with the default key.
+///
+/// There is no signing key: the service never issues tokens, so it has no use
+/// for a private key. The default key only verifies tokens without a `kid`.
+pub struct Keyset {
+    keys: HashMap<String, Key>,
+    /// key of tokens without a `kid`
+    default: Option<Key>,
+    validation: Validation,
+}
+
+impl Keyset {
+    pub fn load(jwt_config: &JwtConfig) -> Result<Self> {
+        let mut keys = HashMap::new();
+
+        for key in &jwt_config.keys {
+            let decoding = Self::load_key(key)
+                .with_context(|| format!("Failed to load verification key {}", key.kid))?;
+
+            keys.insert(
+                key.kid.clone(),
+                Key {
+                    algorithm: key.algorithm,
+                    decoding,
+                },
+            );
+        }
+
+        for path in &jwt_config.jwks_files {
+            let content = fs::read_to_string(path)
+                .with_context(|| format!("Failed to read jwks file from {:?}", path))?;
+            let jwks: JwkSet = serde_json::from_str(&content)
+                .with_context(|| format!("Failed to parse jwks file {:?}", path))?;
+
+            for jwk in &jwks.keys {
+                let kid = jwk
+                    .common
+                    .key_id
+                    .clone()
+                    .ok_or_else(|| anyhow!("A key in jwks file {:?} has no kid", path))?;
+                let algorithm = jwk
+                    .common
+                    .key_algorithm
+                    .and_then(|alg| serde_json::to_value(alg).ok())
+                    .and_then(|alg| serde_json::from_value::<Algorithm>(alg).ok())
+                    .ok_or_else(|| anyhow!("Key {} in {:?} has no signing alg", kid, path))?;
+                let decoding = DecodingKey::from_jwk(jwk)
+                    .with_context(|| format!("Failed to load key {} from {:?}", kid, path))?;
+
+                keys.insert(
+                    kid,
+                    Key {
+                        algorithm,
+                        decoding,
+                    },
+                );
+            }
+        }
+
+        let default = match &jwt_config.default_kid {
+            Some(kid) => {
+                let key = keys
+                    .get(kid)
+                    .ok_or_else(|| anyhow!("Unknown default key {}", kid))?;
+
+                Some(Key {
+                    algorithm: key.algorithm,
+                    decoding: key.decoding.clone(),
+                })
+            }
+            // without keys of its own the keyset is the secret from Secrets.toml
+            None if keys.is_empty() => SECRET_KEY.get().map(|secret| Key {
+                algorithm: Algorithm::HS256,
+                decoding: DecodingKey::from_secret(secret.as_bytes()),
+            }),
+            None => None,
+        };
+
+        let mut validation = Validation::default();
+        validation.leeway = jwt_config.leeway_secs;
+        validation.validate_nbf = true;
+
+        if !jwt_config.issuer.is_empty() {
+            validation.set_issuer(&jwt_config.issuer);
+        }
+
+        if jwt_config.audience.is_empty() {
+            validation.validate_aud = false;
+        } else {
+            validation.set_audience(&jwt_config.audience);
+        }
+
+        Ok(Self {
+            keys,
+            default,
+            validation,
+        })
+    }
+
+    fn load_key(key: &KeyConfig) -> Result<DecodingKey> {
+        let read = |path: &str| {
+            fs::read(path).with_context(|| format!("Failed to read key file from {:?}", path))
+        };
+
+        match (key.algorithm, &key.secret_file, &key.pem_file) {
+            (Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512, Some(path), None) => {
+                let secret = read(path)?;
+                Ok(DecodingKey::from_secret(secret.trim_ascii()))
+            }
+            (Algorithm::RS256 | Algorithm::RS384 | Algorithm::RS512, None, Some(path)) => {
+                Ok(DecodingKey::from_rsa_pem(&read(path)?)?)
+            }
+            (Algorithm::EdDSA, None, Some(path)) => Ok(DecodingKey::from_ed_pem(&read(path)?)?),
+            (algorithm, _, _) => Err(anyhow!(
+                "{:?} keys need exactly one of secret_file (HS*) or pem_file (RS*, EdDSA)",
+                algorithm
+            )),
+        }
+    }
+
+    /// Verifies the signature and the `exp`, `nbf`, `iss` and `aud` claims of
+    /// `token`, returns its claims.
+    pub fn verify(&self, token: &str) -> Result<TokenClaims, ApiError> {
+        let header = decode_header(token).map_err(auth_error)?;
+
+        let key = match &header.kid {
+            Some(kid) => self
+                .keys
+                .get(kid)
+                .ok_or_else(|| ApiError::new(ErrorCode::Auth, "unknown key id."))?,
+            None => self
+                .default
+                .as_ref()
+                .ok_or_else(|| ApiError::new(ErrorCode::Auth, "token has no key id."))?,
+        };
+
+        // the algorithm of the key decides, never the one the token claims
+        if header.alg != key.algorithm {
+            return Err(ApiError::new(
+                ErrorCode::Auth,
+                "token algorithm doesn't match its key.",
+            ));
+        }
+
+        let mut validation = self.validation.clone();
+        validation.algorithms = vec![key.algorithm];
+
+        decode::<TokenClaims>(token, &key.decoding, &validation)
+            .map(|data| data.claims)
+            .map_err(auth_error)
+    }
+}
+
+fn auth_error(error: jsonwebtoken::errors::Error) -> ApiError {
+    ApiError::new(ErrorCode::Auth, error.to_string())
+}
+
+pub fn init_keyset(jwt_config: &JwtConfig) -> Result<()> {
+    let keyset = Keyset::load(jwt_config)?;
+
+    KEYSET
+        .set(keyset)
+        .map_err(|_| anyhow!("keyset already initialized"))
+}
+
+/// Returns the loaded keyset, or one that rejects every token if
+/// `init_keyset` failed or was never called.
+pub fn get() -> &'static Keyset {
+    KEYSET.get_or_init(|| Keyset {
+        keys: HashMap::new(),
+        default: None,
+        validation: Validation::default(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    const OLD_SECRET: &[u8] = b"the old secret";
+    const NEW_SECRET: &[u8] = b"the new secret";
+
+    /// A keyset with the HS256 keys `old` and `new`, `old` being the default.
+    fn keyset(issuer: &str) -> Keyset {
+        let dir = std::env::temp_dir();
+        let old = dir.join(format!("a4s-old-{}-{}.key", issuer, std::process::id()));
+        let new = dir.join(format!("a4s-new-{}-{}.key", issuer, std::process::id()));
+        fs::write(&old, OLD_SECRET).unwrap();
+        fs::write(&new, NEW_SECRET).unwrap();
+
+        let jwt_config: JwtConfig = toml::from_str(&format!(
+            r#"
+            default_kid = "old"
+            issuer = ["{}"]
+            leeway_secs = 5
+
+            [[keys]]
+            kid = "old"
+            algorithm = "HS256"
+            secret_file = {:?}
+
+            [[keys]]
+            kid = "new"
+            algorithm = "HS256"
+            secret_file = {:?}
+            "#,
+            issuer, old, new
+        ))
+        .unwrap();
+
+        let keyset = Keyset::load(&jwt_config);
+        let _ = fs::remove_file(old);
+        let _ = fs::remove_file(new);
+
+        keyset.unwrap()
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    fn token(kid: Option<&str>, algorithm: Algorithm, secret: &[u8], claims: Value) -> String {
+        let mut header = Header::new(algorithm);
+        header.kid = kid.map(str::to_string);
+
+        encode(&header, &claims, &EncodingKey::from_secret(secret)).unwrap()
+    }
+
+    #[test]
+    fn selects_the_key_by_kid() {
+        let keyset = keyset("kid");
+        let claims = json!({ "sub": "42", "iss": "kid", "exp": now() + 60 });
+
+        let old = token(Some("old"), Algorithm::HS256, OLD_SECRET, claims.clone());
+        let new = token(Some("new"), Algorithm::HS256, NEW_SECRET, claims.clone());
+        assert_eq!(keyset.verify(&old).unwrap().sub, Some(json!("42")));
+        assert_eq!(keyset.verify(&new).unwrap().sub, Some(json!("42")));
+
+        // tokens without a kid are verified with the default key only
+        let default = token(None, Algorithm::HS256, OLD_SECRET, claims.clone());
+        assert!(keyset.verify(&default).is_ok());
+        let default = token(None, Algorithm::HS256, NEW_SECRET, claims.clone());
+        assert!(keyset.verify(&default).is_err());
+
+        let crossed = token(Some("old"), Algorithm::HS256, NEW_SECRET, claims.clone());
+        assert!(keyset.verify(&crossed).is_err());
+
+        let unknown = token(Some("gone"), Algorithm::HS256, OLD_SECRET, claims);
+        let error = keyset.verify(&unknown).unwrap_err();
+        assert_eq!(error.code, ErrorCode::Auth);
+    }
+
+    #[test]
+    fn rejects_algorithms_other_than_the_keys() {
+        let keyset = keyset("alg");
+        let claims = json!({ "iss": "alg", "exp": now() + 60 });
+
+        let token = token(Some("old"), Algorithm::HS512, OLD_SECRET, claims);
+        let error = keyset.verify(&token).unwrap_err();
+        assert_eq!(error.message, "token algorithm doesn't match its key.");
+    }
+
+    #[test]
+    fn checks_time_and_issuer_claims() {
+        let keyset = keyset("time");
+        let now = now();
+
+        for (claims, valid) in [
+            (json!({ "iss": "time", "exp": now + 60, "nbf": now }), true),
+            // within the leeway
+            (
+                json!({ "iss": "time", "exp": now - 2, "nbf": now + 2 }),
+                true,
+            ),
+            (
+                json!({ "iss": "time", "exp": now + 60, "nbf": now + 60 }),
+                false,
+            ),
+            (json!({ "iss": "time", "exp": now - 60 }), false),
+            (json!({ "iss": "time" }), false),
+            (json!({ "iss": "someone else", "exp": now + 60 }), false),
+        ] {
+            let token = token(Some("new"), Algorithm::HS256, NEW_SECRET, claims.clone());
+            assert_eq!(keyset.verify(&token).is_ok(), valid, "{}", claims);
+        }
+    }
+
+    #[test]
+    fn refuses_an_unknown_default_key() {
+        let jwt_config: JwtConfig = toml::from_str(r#"default_kid = "missing""#).unwrap();
+        assert!(Keyset::load(&jwt_config).is_err());
+    }
+}
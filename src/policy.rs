@@ -0,0 +1,777 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::OnceLock;
+
+use crate::auth::TokenClaims;
+use crate::error::{ApiError, ErrorCode};
+
+pub static POLICY: OnceLock<Policy> = OnceLock::new();
+
+/// Which tables, actions and columns the holders of a role may use, loaded
+/// from the policy file.
+///
+/// Everything that is not explicitly allowed is denied, a token without a
+/// role the policy knows can't do anything.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Policy {
+    /// role of tokens that carry no role or scope at all
+    pub default_role: Option<String>,
+    /// per table, columns that are never returned to any role
+    pub hidden: HashMap<String, Vec<String>>,
+    pub roles: HashMap<String, RolePolicy>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct RolePolicy {
+    /// whether the role may use the `/admin` endpoints
+    pub admin: bool,
+    /// per table, `"*"` applies to every table without an entry of its own
+    pub tables: HashMap<String, TablePolicy>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct TablePolicy {
+    /// allowed actions, `"*"` allows all of them
+    pub actions: Vec<String>,
+    /// columns requests may write or filter on, all of them if not set
+    pub columns: Option<Vec<String>>,
+    /// columns that have to equal a claim of the token, limiting the role to
+    /// the rows it owns
+    pub rows: HashMap<String, RowRule>,
+    /// columns stripped from every row the role gets back, it can't filter on
+    /// them either
+    pub hidden: Vec<String>,
+    /// columns the role can read but not write
+    pub read_only: Vec<String>,
+}
+
+/// Columns that are stripped from the rows a request gets back.
+#[derive(Debug, Clone, Default)]
+pub struct Mask {
+    hidden: Vec<String>,
+}
+
+impl Mask {
+    pub fn hides(&self, column: &str) -> bool {
+        self.hidden.iter().any(|c| c == column)
+    }
+
+    pub fn apply(&self, rows: &mut [Value]) {
+        if self.hidden.is_empty() {
+            return;
+        }
+
+        for row in rows.iter_mut().filter_map(Value::as_object_mut) {
+            for column in &self.hidden {
+                row.remove(column);
+            }
+        }
+    }
+}
+
+/// The claim a column has to equal, as `uid = "sub"`, or as
+/// `uid = { claim = "sub", number = true }` for numeric columns that the
+/// claim holds as a string.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum RowRule {
+    Claim(String),
+    Typed {
+        claim: String,
+        #[serde(default)]
+        number: bool,
+    },
+}
+
+impl RowRule {
+    /// The value the column has to have for the holder of `claims`.
+    fn value(&self, claims: &TokenClaims) -> Option<Value> {
+        let (claim, number) = match self {
+            RowRule::Claim(claim) => (claim, false),
+            RowRule::Typed { claim, number } => (claim, *number),
+        };
+
+        let value = claims.claim(claim)?.clone();
+
+        match value {
+            Value::String(s) if number => s.parse::<i64>().ok().map(Value::from),
+            value => Some(value),
+        }
+    }
+}
+
+impl TablePolicy {
+    fn allows_action(&self, action: &str) -> bool {
+        self.actions.iter().any(|a| a == "*" || a == action)
+    }
+
+    fn allows_column(&self, column: &str) -> bool {
+        match &self.columns {
+            Some(columns) => columns.iter().any(|c| c == "*" || c == column),
+            None => true,
+        }
+    }
+}
+
+impl Policy {
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read policy file from {:?}", path))?;
+
+        toml::from_str(&content).with_context(|| format!("Failed to parse policy file {:?}", path))
+    }
+
+    /// Checks that the holder of `claims` may run the raw request `request`,
+    /// the table, action and columns are read straight from its json.
+    ///
+    /// Row rules of the table are written into the request: they replace the
+    /// filters on their columns, and the values written to them. Returns the
+    /// mask to apply to the rows the request gets back.
+    pub fn authorize(&self, claims: &TokenClaims, request: &mut Value) -> Result<Mask, ApiError> {
+        let (forced, mask) = self.check(claims, request)?;
+
+        for (column, value) in forced {
+            force_column(request, &column, value);
+        }
+
+        Ok(mask)
+    }
+
+    /// Checks the request, returns the values its row rules force on it and
+    /// the mask for its rows.
+    fn check(
+        &self,
+        claims: &TokenClaims,
+        request: &Value,
+    ) -> Result<(Vec<(String, Value)>, Mask), ApiError> {
+        let table = request
+            .get("table")
+            .and_then(Value::as_str)
+            .unwrap_or_default();
+        let action = action_name(request);
+        let columns = referenced_columns(request);
+
+        let policies: Vec<&TablePolicy> = self
+            .roles_of(claims)
+            .filter_map(|role| self.roles.get(role))
+            .filter_map(|role| role.tables.get(table).or_else(|| role.tables.get("*")))
+            .filter(|policy| policy.allows_action(action))
+            .collect();
+
+        if policies.is_empty() {
+            return Err(forbidden(format!(
+                "{} on {} is not allowed.",
+                action, table
+            )));
+        }
+
+        // every column has to be allowed by one of the roles that allow the action
+        for column in columns {
+            if !policies.iter().any(|policy| policy.allows_column(column)) {
+                return Err(
+                    forbidden(format!("Column {} of {} is not allowed.", column, table))
+                        .with_column(column),
+                );
+            }
+        }
+
+        let never_returned = self.hidden.get(table).map_or(&[][..], Vec::as_slice);
+
+        // a column is hidden or read-only only if it is for every role that
+        // allows the action, or if the policy never returns it
+        let hidden = |column: &str| {
+            never_returned.iter().any(|c| c == column)
+                || policies
+                    .iter()
+                    .all(|p| p.hidden.iter().any(|c| c == column))
+        };
+        let read_only = |column: &str| {
+            policies
+                .iter()
+                .all(|p| p.read_only.iter().any(|c| c == column))
+        };
+
+        for column in filtered_columns(request) {
+            if hidden(column) {
+                return Err(forbidden(format!(
+                    "Column {} of {} can't be filtered on.",
+                    column, table
+                ))
+                .with_column(column));
+            }
+        }
+
+        for column in written_columns(request) {
+            if read_only(column) {
+                return Err(
+                    forbidden(format!("Column {} of {} is read-only.", column, table))
+                        .with_column(column),
+                );
+            }
+        }
+
+        let mut hidden_columns: Vec<String> = policies
+            .iter()
+            .flat_map(|policy| &policy.hidden)
+            .filter(|column| hidden(column))
+            .chain(never_returned)
+            .cloned()
+            .collect();
+        hidden_columns.sort_unstable();
+        hidden_columns.dedup();
+
+        let mask = Mask {
+            hidden: hidden_columns,
+        };
+
+        // a role that may touch every row wins over the ones limited to their own
+        if policies.iter().any(|policy| policy.rows.is_empty()) {
+            return Ok((Vec::new(), mask));
+        }
+
+        // every role that allows the action is limited to its own rows, so the
+        // request may only touch the rows all of their rules allow
+        let mut forced: Vec<(String, Value)> = Vec::new();
+
+        for (column, rule) in policies.iter().flat_map(|policy| &policy.rows) {
+            let value = rule.value(claims).ok_or_else(|| {
+                forbidden(format!("The token has no claim to limit {} to.", table))
+            })?;
+
+            match forced
+                .iter()
+                .find(|(forced_column, _)| forced_column == column)
+            {
+                Some((_, forced_value)) if *forced_value != value => {
+                    return Err(forbidden(format!(
+                        "The roles of the token limit {} to different rows.",
+                        table
+                    ))
+                    .with_column(column));
+                }
+                Some(_) => {}
+                None => forced.push((column.clone(), value)),
+            }
+        }
+
+        // an upsert updates the row it conflicts with, whatever it writes, so
+        // only conflicts on the rule columns keep it to the rows it owns
+        if action == "Upsert" {
+            let conflict = keys_of_array(request.get("conflict"));
+
+            for (column, _) in &forced {
+                if !conflict.contains(&column.as_str()) {
+                    return Err(forbidden(format!(
+                        "Upserts on {} have to conflict on {}.",
+                        table, column
+                    ))
+                    .with_column(column));
+                }
+            }
+        }
+
+        Ok((forced, mask))
+    }
+
+    /// Whether one of the roles of `claims` may use the admin endpoints.
+    pub fn is_admin(&self, claims: &TokenClaims) -> bool {
+        self.roles_of(claims)
+            .filter_map(|role| self.roles.get(role))
+            .any(|role| role.admin)
+    }
+
+    /// The roles the policy applies to the holder of `claims`, those of the
+    /// token or the default role for tokens without any.
+    pub fn roles_of<'a>(&'a self, claims: &'a TokenClaims) -> impl Iterator<Item = &'a str> {
+        let roles = claims.roles();
+
+        let default_role = if roles.is_empty() {
+            self.default_role.as_deref()
+        } else {
+            None
+        };
+
+        roles.into_iter().chain(default_role)
+    }
+}
+
+/// Makes the request only match, and only write, rows where `column` is `value`.
+fn force_column(request: &mut Value, column: &str, value: Value) {
+    let action = action_name(request);
+    let filtered = matches!(action, "Retrieve" | "Update" | "Delete");
+
+    let Some(request) = request.as_object_mut() else {
+        return;
+    };
+
+    if filtered {
+        // a missing or `null` filter would match every row
+        let filters = request.entry("filters").or_insert(Value::Null);
+        if !filters.is_object() {
+            *filters = Value::Object(Map::new());
+        }
+
+        let conditions = filters["where"].take();
+        let mut conditions = match conditions {
+            Value::Object(conditions) => conditions,
+            _ => Map::new(),
+        };
+        conditions.insert(column.to_string(), value.clone());
+        filters["where"] = Value::Object(conditions);
+    }
+
+    if let Some(values) = request.get_mut("values").and_then(Value::as_object_mut) {
+        values.insert(column.to_string(), value.clone());
+    }
+
+    match request.get_mut("bulk_values") {
+        Some(Value::Array(rows)) => {
+            for row in rows.iter_mut().filter_map(Value::as_object_mut) {
+                row.insert(column.to_string(), value.clone());
+            }
+        }
+        Some(Value::Object(row)) => {
+            row.insert(column.to_string(), value);
+        }
+        _ => {}
+    }
+}
+
+fn forbidden(message: String) -> ApiError {
+    ApiError::new(ErrorCode::Forbidden, message)
+}
+
+/// Name of the action of a raw request, the variant name for actions that
+/// carry data like `{ "Delete": ... }`. Subscriptions need the right to
+/// retrieve.
+pub fn action_name(request: &Value) -> &str {
+    let action = match request.get("action") {
+        Some(Value::String(action)) => action.as_str(),
+        Some(Value::Object(action)) => action.keys().next().map_or("", String::as_str),
+        _ => "",
+    };
+
+    match action {
+        "Subscribe" => "Retrieve",
+        action => action,
+    }
+}
+
+/// Columns a raw request writes to or filters on.
+fn referenced_columns(request: &Value) -> Vec<&str> {
+    let mut columns = written_columns(request);
+    columns.extend(filtered_columns(request));
+
+    columns.extend(keys_of_array(request.get("conflict")));
+
+    columns.sort_unstable();
+    columns.dedup();
+    columns
+}
+
+/// Columns a raw request writes to.
+fn written_columns(request: &Value) -> Vec<&str> {
+    let mut columns = keys(request.get("values"));
+
+    match request.get("bulk_values") {
+        Some(Value::Array(rows)) => {
+            for row in rows {
+                columns.extend(keys(Some(row)));
+            }
+        }
+        bulk_values => columns.extend(keys(bulk_values)),
+    }
+
+    columns
+}
+
+/// Columns a raw request filters or orders by.
+fn filtered_columns(request: &Value) -> Vec<&str> {
+    let filters = request.get("filters");
+    let mut columns = keys(filters.and_then(|f| f.get("where")));
+
+    if let Some(column) = filters
+        .and_then(|f| f.get("order_by"))
+        .and_then(|o| o.get("column"))
+        .and_then(Value::as_str)
+    {
+        columns.push(column);
+    }
+
+    columns
+}
+
+/// The column names of an array like `conflict`.
+fn keys_of_array(value: Option<&Value>) -> Vec<&str> {
+    value
+        .and_then(Value::as_array)
+        .map(|columns| columns.iter().filter_map(Value::as_str).collect())
+        .unwrap_or_default()
+}
+
+fn keys(value: Option<&Value>) -> Vec<&str> {
+    value
+        .and_then(Value::as_object)
+        .map(|map| map.keys().map(String::as_str).collect())
+        .unwrap_or_default()
+}
+
+pub fn init_policy(path: &str) -> Result<()> {
+    let policy = Policy::load(Path::new(path))?;
+
+    POLICY
+        .set(policy)
+        .map_err(|_| anyhow::anyhow!("policy already initialized"))
+}
+
+/// Returns the loaded policy, or an empty one that denies everything if
+/// `init_policy` failed or was never called.
+pub fn get() -> &'static Policy {
+    POLICY.get_or_init(Policy::default)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    const POLICY: &str = r#"
+        default_role = "guest"
+
+        [hidden]
+        auth_users = ["password_hash"]
+
+        [roles.backend]
+        admin = true
+
+        [roles.backend.tables."*"]
+        actions = ["*"]
+
+        [roles.guest.tables.themes]
+        actions = ["Retrieve"]
+
+        [roles.frontend.tables.users]
+        actions = ["Retrieve", "Update", "BulkInsert", "Upsert"]
+        columns = ["uid", "org", "username", "email", "password_hash"]
+        rows = { uid = { claim = "sub", number = true } }
+        hidden = ["password_hash"]
+        read_only = ["uid", "email"]
+
+        [roles.support.tables.users]
+        actions = ["Retrieve", "Update"]
+        hidden = ["password_hash", "email"]
+
+        [roles.member.tables.users]
+        actions = ["Retrieve"]
+        rows = { org = "org" }
+
+        [roles.delegate.tables.users]
+        actions = ["Retrieve"]
+        rows = { uid = { claim = "for", number = true } }
+    "#;
+
+    #[test]
+    fn denies_by_default() {
+        let policy: Policy = toml::from_str(POLICY).unwrap();
+        let frontend: TokenClaims =
+            serde_json::from_value(json!({ "sub": "42", "role": "frontend" })).unwrap();
+
+        let mut request = json!({ "table": "themes", "action": "Insert", "values": {} });
+        let error = policy.authorize(&frontend, &mut request).unwrap_err();
+        assert_eq!(error.code, ErrorCode::Forbidden);
+
+        // an unknown role, and a policy without any roles, allow nothing
+        let stranger: TokenClaims = serde_json::from_value(json!({ "role": "stranger" })).unwrap();
+        let mut request = json!({ "table": "users", "action": "Retrieve" });
+        assert!(policy.authorize(&stranger, &mut request).is_err());
+        assert!(Policy::default()
+            .authorize(&TokenClaims::default(), &mut request)
+            .is_err());
+    }
+
+    #[test]
+    fn gives_tokens_without_roles_the_default_role() {
+        let policy: Policy = toml::from_str(POLICY).unwrap();
+        let anonymous = TokenClaims::default();
+
+        let mut request = json!({ "table": "themes", "action": "Retrieve" });
+        assert!(policy.authorize(&anonymous, &mut request).is_ok());
+
+        let mut request = json!({ "table": "users", "action": "Retrieve" });
+        assert!(policy.authorize(&anonymous, &mut request).is_err());
+        assert_eq!(policy.roles_of(&anonymous).collect::<Vec<_>>(), ["guest"]);
+
+        // a token with roles never gets the default one
+        let frontend: TokenClaims =
+            serde_json::from_value(json!({ "sub": "42", "role": "frontend" })).unwrap();
+        assert_eq!(policy.roles_of(&frontend).collect::<Vec<_>>(), ["frontend"]);
+
+        let mut request = json!({ "table": "themes", "action": "Retrieve" });
+        assert!(policy.authorize(&frontend, &mut request).is_err());
+    }
+
+    #[test]
+    fn only_admin_roles_are_admins() {
+        let policy: Policy = toml::from_str(POLICY).unwrap();
+
+        let frontend: TokenClaims = serde_json::from_value(json!({ "role": "frontend" })).unwrap();
+        let scoped: TokenClaims =
+            serde_json::from_value(json!({ "scope": "read backend" })).unwrap();
+
+        assert!(!policy.is_admin(&frontend));
+        assert!(!policy.is_admin(&TokenClaims::default()));
+        assert!(policy.is_admin(&scoped));
+    }
+
+    #[test]
+    fn checks_columns() {
+        let policy: Policy = toml::from_str(POLICY).unwrap();
+        let frontend: TokenClaims =
+            serde_json::from_value(json!({ "sub": "42", "role": "frontend" })).unwrap();
+
+        let mut request =
+            json!({ "table": "users", "action": "Update", "values": { "owner": true } });
+        let error = policy.authorize(&frontend, &mut request).unwrap_err();
+        assert_eq!(error.code, ErrorCode::Forbidden);
+        assert_eq!(error.column.as_deref(), Some("owner"));
+
+        let mut request = json!({
+            "table": "users",
+            "action": "Retrieve",
+            "filters": { "where": { "owner": true } }
+        });
+        assert!(policy.authorize(&frontend, &mut request).is_err());
+
+        let mut request = json!({
+            "table": "users",
+            "action": "Retrieve",
+            "filters": { "order_by": { "column": "owner" } }
+        });
+        assert!(policy.authorize(&frontend, &mut request).is_err());
+    }
+
+    #[test]
+    fn forces_row_rules_into_filters_and_values() {
+        let policy: Policy = toml::from_str(POLICY).unwrap();
+        let frontend: TokenClaims =
+            serde_json::from_value(json!({ "sub": "42", "role": "frontend" })).unwrap();
+
+        let mut request = json!({
+            "table": "users",
+            "action": "Update",
+            "values": { "username": "new" },
+            "filters": { "where": { "uid": 7, "username": "old" } }
+        });
+        policy.authorize(&frontend, &mut request).unwrap();
+        assert_eq!(
+            request["filters"]["where"],
+            json!({ "uid": 42, "username": "old" })
+        );
+        assert_eq!(request["values"], json!({ "username": "new", "uid": 42 }));
+
+        // a missing or null filter would match every row
+        let mut request = json!({ "table": "users", "action": "Retrieve", "filters": null });
+        policy.authorize(&frontend, &mut request).unwrap();
+        assert_eq!(request["filters"]["where"], json!({ "uid": 42 }));
+
+        let mut request = json!({
+            "table": "users",
+            "action": "BulkInsert",
+            "bulk_values": [{ "username": "a" }, { "username": "b" }]
+        });
+        policy.authorize(&frontend, &mut request).unwrap();
+        assert_eq!(request["bulk_values"][0]["uid"], 42);
+        assert_eq!(request["bulk_values"][1]["uid"], 42);
+    }
+
+    #[test]
+    fn force_column_only_filters_reads_updates_and_deletes() {
+        let mut insert = json!({ "table": "users", "action": "Insert", "values": {} });
+        force_column(&mut insert, "uid", json!(1));
+        assert_eq!(
+            insert,
+            json!({ "table": "users", "action": "Insert", "values": { "uid": 1 } })
+        );
+
+        let mut delete = json!({ "table": "users", "action": { "Delete": null } });
+        force_column(&mut delete, "uid", json!(1));
+        assert_eq!(delete["filters"]["where"], json!({ "uid": 1 }));
+
+        let mut upsert = json!({ "table": "users", "action": "Upsert", "values": { "a": 1 } });
+        force_column(&mut upsert, "uid", json!(1));
+        assert_eq!(upsert["values"], json!({ "a": 1, "uid": 1 }));
+        assert!(upsert.get("filters").is_none());
+    }
+
+    #[test]
+    fn keeps_upserts_to_owned_rows() {
+        let policy: Policy = toml::from_str(POLICY).unwrap();
+        let frontend: TokenClaims =
+            serde_json::from_value(json!({ "sub": "42", "role": "frontend" })).unwrap();
+
+        let mut request = json!({
+            "table": "users",
+            "action": "Upsert",
+            "values": { "uid": 7, "username": "new" },
+            "conflict": ["uid"]
+        });
+        policy.authorize(&frontend, &mut request).unwrap();
+        assert_eq!(request["values"], json!({ "uid": 42, "username": "new" }));
+
+        // conflicting on another column would update a row of someone else
+        let mut request = json!({
+            "table": "users",
+            "action": "Upsert",
+            "values": { "username": "taken" },
+            "conflict": ["username"]
+        });
+        let error = policy.authorize(&frontend, &mut request).unwrap_err();
+        assert_eq!(error.code, ErrorCode::Forbidden);
+        assert_eq!(error.column.as_deref(), Some("uid"));
+
+        let mut request = json!({ "table": "users", "action": "Upsert", "values": {} });
+        assert!(policy.authorize(&frontend, &mut request).is_err());
+    }
+
+    #[test]
+    fn denies_tokens_without_the_claim_of_a_row_rule() {
+        let policy: Policy = toml::from_str(POLICY).unwrap();
+        let no_sub: TokenClaims = serde_json::from_value(json!({ "role": "frontend" })).unwrap();
+
+        let mut request = json!({ "table": "users", "action": "Retrieve" });
+        assert!(policy.authorize(&no_sub, &mut request).is_err());
+    }
+
+    #[test]
+    fn a_role_without_row_rules_touches_every_row() {
+        let policy: Policy = toml::from_str(POLICY).unwrap();
+        let both: TokenClaims =
+            serde_json::from_value(json!({ "sub": "42", "roles": ["frontend", "support"] }))
+                .unwrap();
+
+        let mut request = json!({ "table": "users", "action": "Retrieve" });
+        policy.authorize(&both, &mut request).unwrap();
+        assert!(request.get("filters").is_none());
+
+        // only frontend allows bulk inserts, so its rules apply alone
+        let mut request = json!({ "table": "users", "action": "BulkInsert", "bulk_values": [{}] });
+        policy.authorize(&both, &mut request).unwrap();
+        assert_eq!(request["bulk_values"][0]["uid"], 42);
+    }
+
+    #[test]
+    fn intersects_row_rules_of_roles() {
+        let policy: Policy = toml::from_str(POLICY).unwrap();
+
+        let member: TokenClaims = serde_json::from_value(
+            json!({ "sub": "42", "org": "acme", "roles": ["frontend", "member"] }),
+        )
+        .unwrap();
+        let mut request = json!({ "table": "users", "action": "Retrieve" });
+        policy.authorize(&member, &mut request).unwrap();
+        assert_eq!(
+            request["filters"]["where"],
+            json!({ "uid": 42, "org": "acme" })
+        );
+
+        // rules that can't both hold are denied instead of picking one
+        let delegate: TokenClaims = serde_json::from_value(
+            json!({ "sub": "42", "for": "7", "roles": ["frontend", "delegate"] }),
+        )
+        .unwrap();
+        let mut request = json!({ "table": "users", "action": "Retrieve" });
+        let error = policy.authorize(&delegate, &mut request).unwrap_err();
+        assert_eq!(error.column.as_deref(), Some("uid"));
+
+        let same: TokenClaims = serde_json::from_value(
+            json!({ "sub": "42", "for": "42", "roles": ["frontend", "delegate"] }),
+        )
+        .unwrap();
+        let mut request = json!({ "table": "users", "action": "Retrieve" });
+        policy.authorize(&same, &mut request).unwrap();
+        assert_eq!(request["filters"]["where"], json!({ "uid": 42 }));
+    }
+
+    #[test]
+    fn masks_hidden_and_read_only_columns() {
+        let policy: Policy = toml::from_str(POLICY).unwrap();
+        let frontend: TokenClaims =
+            serde_json::from_value(json!({ "sub": "42", "role": "frontend" })).unwrap();
+
+        let mut request = json!({ "table": "users", "action": "Retrieve" });
+        let mask = policy.authorize(&frontend, &mut request).unwrap();
+        let mut rows = vec![json!({ "uid": 42, "email": "a@b.c", "password_hash": "x" })];
+        mask.apply(&mut rows);
+        assert_eq!(rows, [json!({ "uid": 42, "email": "a@b.c" })]);
+
+        // filtering or ordering by a hidden column would tell its value apart
+        let mut request = json!({
+            "table": "users",
+            "action": "Retrieve",
+            "filters": { "order_by": { "column": "password_hash" } }
+        });
+        let error = policy.authorize(&frontend, &mut request).unwrap_err();
+        assert_eq!(error.column.as_deref(), Some("password_hash"));
+
+        let mut request =
+            json!({ "table": "users", "action": "Update", "values": { "email": "a@b.c" } });
+        let error = policy.authorize(&frontend, &mut request).unwrap_err();
+        assert_eq!(error.column.as_deref(), Some("email"));
+    }
+
+    #[test]
+    fn masks_only_what_every_allowing_role_masks() {
+        let policy: Policy = toml::from_str(POLICY).unwrap();
+        let both: TokenClaims =
+            serde_json::from_value(json!({ "sub": "42", "roles": ["frontend", "support"] }))
+                .unwrap();
+
+        // support hides the email but frontend does not, and only frontend
+        // makes it read-only
+        let mut request = json!({ "table": "users", "action": "Retrieve" });
+        let mask = policy.authorize(&both, &mut request).unwrap();
+        let mut rows = vec![json!({ "uid": 42, "email": "a@b.c", "password_hash": "x" })];
+        mask.apply(&mut rows);
+        assert_eq!(rows, [json!({ "uid": 42, "email": "a@b.c" })]);
+
+        let mut request =
+            json!({ "table": "users", "action": "Update", "values": { "email": "a@b.c" } });
+        assert!(policy.authorize(&both, &mut request).is_ok());
+    }
+
+    #[test]
+    fn never_returns_globally_hidden_columns() {
+        let policy: Policy = toml::from_str(POLICY).unwrap();
+        let backend: TokenClaims = serde_json::from_value(json!({ "role": "backend" })).unwrap();
+
+        let mut request = json!({ "table": "auth_users", "action": "Retrieve" });
+        let mask = policy.authorize(&backend, &mut request).unwrap();
+        let mut rows = vec![json!({ "uid": 1, "password_hash": "x" })];
+        mask.apply(&mut rows);
+        assert_eq!(rows, [json!({ "uid": 1 })]);
+
+        // not even a role allowed everything can filter on them
+        let mut request = json!({
+            "table": "auth_users",
+            "action": "Retrieve",
+            "filters": { "where": { "password_hash": "x" } }
+        });
+        assert!(policy.authorize(&backend, &mut request).is_err());
+
+        // but it can still write them
+        let mut request = json!({
+            "table": "auth_users",
+            "action": "Insert",
+            "values": { "uid": 1, "password_hash": "x" }
+        });
+        assert!(policy.authorize(&backend, &mut request).is_ok());
+    }
+}
@@ -0,0 +1,127 @@
+use acid4sigmas_models::models::db::DatabaseResponse;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::cache::CacheMode;
+use crate::error::ApiError;
+
+/// Client supplied identifier of a request, echoed in its response.
+pub type RequestId = Value;
+
+/// Outcome of a single request.
+pub type Reply = Result<DatabaseResponse<Value>, ApiError>;
+
+/// Subprotocol a client requests to exchange messagepack instead of json.
+pub const MSGPACK_PROTOCOL: &str = "a4s.msgpack";
+
+/// Subprotocol for json, for browsers that send their token as a subprotocol
+/// and need one to be confirmed.
+pub const JSON_PROTOCOL: &str = "a4s.json";
+
+/// Subprotocol for messagepack whose large binary frames are deflated by the
+/// api itself, not the websocket `permessage-deflate` extension, see
+/// `ws::framing`.
+pub const MSGPACK_DEFLATE_PROTOCOL: &str = "a4s.msgpack+deflate";
+
+/// Prefix of the subprotocol that carries the token, `a4s.bearer.<token>`.
+pub const TOKEN_PROTOCOL_PREFIX: &str = "a4s.bearer.";
+
+/// Encoding of the messages of a session.
+///
+/// Text frames always carry json, binary frames carry the encoding the client
+/// negotiated, see `ws::framing`. A reply uses the encoding of its request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    Json,
+    MessagePack,
+}
+
+impl Codec {
+    pub fn decode(self, bytes: &[u8]) -> Result<Value, String> {
+        match self {
+            Codec::Json => serde_json::from_slice(bytes).map_err(|e| e.to_string()),
+            Codec::MessagePack => rmp_serde::from_slice(bytes).map_err(|e| e.to_string()),
+        }
+    }
+
+    pub fn encode_reply(self, id: Option<&RequestId>, reply: &Reply) -> Result<Vec<u8>, String> {
+        let value = reply_value(id, reply).map_err(|e| e.to_string())?;
+        self.encode(&value)
+    }
+
+    pub fn encode(self, value: &Value) -> Result<Vec<u8>, String> {
+        match self {
+            Codec::Json => serde_json::to_vec(value).map_err(|e| e.to_string()),
+            // named, so maps keep their keys just like in json
+            Codec::MessagePack => rmp_serde::to_vec_named(value).map_err(|e| e.to_string()),
+        }
+    }
+}
+
+/// Shape every error is sent to clients in.
+#[derive(Debug, Serialize)]
+struct ErrorReply<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<&'a RequestId>,
+    error: &'a ApiError,
+}
+
+/// Options of a message sent by a client over the websocket that only concern
+/// this api.
+///
+/// They sit at the top level of the json next to the fields of the
+/// `DatabaseRequest`, which is parsed on its own once the policy has checked
+/// it. The id is read with `request_id`.
+#[derive(Debug, Deserialize)]
+pub struct ClientRequest {
+    /// bypass the cache completely
+    #[serde(default)]
+    pub no_cache: bool,
+    /// skip the cache lookup but store the fresh result
+    #[serde(default)]
+    pub refresh: bool,
+    /// id of the `Begin` request of the transaction to run the request in
+    #[serde(default)]
+    pub transaction: Option<RequestId>,
+}
+
+impl ClientRequest {
+    pub fn cache_mode(&self) -> CacheMode {
+        if self.no_cache {
+            CacheMode::NoCache
+        } else if self.refresh {
+            CacheMode::Refresh
+        } else {
+            CacheMode::Default
+        }
+    }
+}
+
+/// Reads the id of a request from its raw json, without requiring the rest of
+/// the request to be valid.
+pub fn request_id(value: &Value) -> Option<RequestId> {
+    value.get("id").filter(|id| !id.is_null()).cloned()
+}
+
+/// Builds the reply to a request, tagged with the id of that request.
+///
+/// Object responses get the id as an extra field, anything else is wrapped
+/// in `{ "id": ..., "data": ... }`. Errors are always sent as
+/// `{ "id": ..., "error": ... }`.
+pub fn reply_value(id: Option<&RequestId>, reply: &Reply) -> serde_json::Result<Value> {
+    let response = match reply {
+        Ok(response) => response,
+        Err(error) => return serde_json::to_value(ErrorReply { id, error }),
+    };
+
+    let value = match (id, serde_json::to_value(response)?) {
+        (Some(id), Value::Object(mut map)) => {
+            map.insert("id".to_string(), id.clone());
+            Value::Object(map)
+        }
+        (Some(id), data) => json!({ "id": id, "data": data }),
+        (None, value) => value,
+    };
+
+    Ok(value)
+}
@@ -0,0 +1,442 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::auth::TokenClaims;
+use crate::config::{self, Limits};
+use crate::error::{ApiError, ErrorCode};
+use crate::policy;
+
+const DAY_SECS: u64 = 24 * 60 * 60;
+
+lazy_static::lazy_static! {
+    pub static ref RATE_LIMITER: RateLimiter = RateLimiter::default();
+}
+
+/// Numbers the sessions of tokens that have no subject, without an address.
+static ANONYMOUS_SESSIONS: AtomicU64 = AtomicU64::new(0);
+
+/// Which budget a request is taken from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Read,
+    Write,
+}
+
+impl Kind {
+    /// What a raw request counts as, `None` for requests that are not limited.
+    pub fn of(request: &Value) -> Option<Self> {
+        match request.get("action").and_then(Value::as_str) {
+            Some("Retrieve" | "Subscribe") => Some(Kind::Read),
+            Some("Unsubscribe" | "Begin" | "Commit" | "Rollback") => None,
+            // including actions that carry data, like `{ "Delete": ... }`
+            _ => Some(Kind::Write),
+        }
+    }
+}
+
+/// How many requests a raw request counts as, a batch counts every step.
+pub fn cost(request: &Value) -> u64 {
+    if request.get("action").and_then(Value::as_str) != Some("Batch") {
+        return 1;
+    }
+
+    request
+        .get("steps")
+        .and_then(Value::as_array)
+        .map_or(1, |steps| steps.len().max(1) as u64)
+}
+
+/// Token bucket that starts out full.
+#[derive(Default)]
+struct Bucket {
+    tokens: f64,
+    updated: Option<Instant>,
+}
+
+impl Bucket {
+    /// Refills the bucket, returns how long until a request of `cost` fits
+    /// into it. A request that costs more than the burst needs a full bucket,
+    /// and leaves it in debt.
+    fn wait(&mut self, rate: f64, burst: f64, cost: f64) -> Option<Duration> {
+        if rate <= 0.0 {
+            return None;
+        }
+
+        let burst = burst.max(1.0);
+        let now = Instant::now();
+
+        self.tokens = match self.updated {
+            Some(updated) => {
+                (self.tokens + now.duration_since(updated).as_secs_f64() * rate).min(burst)
+            }
+            None => burst,
+        };
+        self.updated = Some(now);
+
+        let needed = cost.min(burst);
+        (self.tokens < needed).then(|| Duration::from_secs_f64((needed - self.tokens) / rate))
+    }
+
+    fn take(&mut self, rate: f64, cost: f64) {
+        if rate > 0.0 {
+            self.tokens -= cost;
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Counters {
+    pub reads: u64,
+    pub writes: u64,
+    /// requests that were rejected
+    pub limited: u64,
+    pub in_flight: usize,
+    /// requests of the current utc day
+    pub today: u64,
+}
+
+/// Requests of a single token subject or peer address.
+#[derive(Default)]
+struct Usage {
+    reads: Bucket,
+    writes: Bucket,
+    counters: Counters,
+    /// utc day `counters.today` counts the requests of
+    day: u64,
+}
+
+impl Usage {
+    /// Checks that a request of `kind` counting as `cost` requests fits into
+    /// `limits`.
+    fn check(&mut self, limits: &Limits, kind: Kind, cost: u64, day: u64) -> Result<(), ApiError> {
+        if self.day != day {
+            self.day = day;
+            self.counters.today = 0;
+        }
+
+        let result = self.wait(limits, kind, cost);
+
+        if result.is_err() {
+            self.counters.limited += 1;
+        }
+
+        result
+    }
+
+    fn wait(&mut self, limits: &Limits, kind: Kind, cost: u64) -> Result<(), ApiError> {
+        if limits.daily_quota > 0 && self.counters.today + cost > limits.daily_quota {
+            return Err(rate_limited("Daily quota used up.", until_tomorrow()));
+        }
+
+        if limits.max_concurrent > 0 && self.counters.in_flight >= limits.max_concurrent {
+            return Err(rate_limited(
+                "Too many requests running at the same time.",
+                Duration::from_secs(1),
+            ));
+        }
+
+        let cost = cost as f64;
+        let waited = match kind {
+            Kind::Read => self
+                .reads
+                .wait(limits.reads_per_sec, limits.read_burst, cost),
+            Kind::Write => self
+                .writes
+                .wait(limits.writes_per_sec, limits.write_burst, cost),
+        };
+
+        match waited {
+            Some(retry_after) => Err(rate_limited("Too many requests.", retry_after)),
+            None => Ok(()),
+        }
+    }
+
+    fn take(&mut self, limits: &Limits, kind: Kind, cost: u64) {
+        match kind {
+            Kind::Read => {
+                self.reads.take(limits.reads_per_sec, cost as f64);
+                self.counters.reads += cost;
+            }
+            Kind::Write => {
+                self.writes.take(limits.writes_per_sec, cost as f64);
+                self.counters.writes += cost;
+            }
+        }
+
+        self.counters.today += cost;
+        self.counters.in_flight += 1;
+    }
+}
+
+#[derive(Default)]
+struct State {
+    subjects: HashMap<String, Usage>,
+    peers: HashMap<IpAddr, Usage>,
+    /// utc day the identities of earlier days were last forgotten on
+    pruned: u64,
+}
+
+/// Limits how many requests each token subject and each peer address may
+/// make, with `[rate_limit]` of `Config.toml`.
+#[derive(Default)]
+pub struct RateLimiter {
+    state: Mutex<State>,
+}
+
+/// A request that passed the limits, counts as running until it is dropped.
+#[derive(Default)]
+pub struct Permit {
+    subject: Option<String>,
+    peer: Option<IpAddr>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RateLimitStats {
+    pub subjects: HashMap<String, Counters>,
+    pub peers: HashMap<String, Counters>,
+}
+
+impl RateLimiter {
+    /// Admits a request of `kind` made by `subject` with `claims` from `peer`
+    /// that counts as `cost` requests, or returns a `rate_limit` error that
+    /// says when to retry.
+    pub fn acquire(
+        &self,
+        subject: &str,
+        claims: &TokenClaims,
+        peer: Option<IpAddr>,
+        kind: Kind,
+        cost: u64,
+    ) -> Result<Permit, ApiError> {
+        let rate_limit = &config::get().rate_limit;
+
+        if !rate_limit.enabled {
+            return Ok(Permit::default());
+        }
+
+        let limits = policy::get()
+            .roles_of(claims)
+            .find_map(|role| rate_limit.roles.get(role))
+            .unwrap_or(&rate_limit.default);
+        let day = today();
+
+        let mut state = self.state.lock().unwrap();
+        state.prune(day);
+
+        let State {
+            subjects, peers, ..
+        } = &mut *state;
+
+        let usage = subjects.entry(subject.to_string()).or_default();
+        usage.check(limits, kind, cost, day)?;
+
+        if let Some(peer) = peer {
+            let peer_usage = peers.entry(peer).or_default();
+            peer_usage.check(&rate_limit.peer, kind, cost, day)?;
+            peer_usage.take(&rate_limit.peer, kind, cost);
+        }
+
+        usage.take(limits, kind, cost);
+
+        Ok(Permit {
+            subject: Some(subject.to_string()),
+            peer,
+        })
+    }
+
+    pub fn stats(&self) -> RateLimitStats {
+        let state = self.state.lock().unwrap();
+        let day = today();
+
+        let counters = |usage: &Usage| {
+            let mut counters = usage.counters.clone();
+            if usage.day != day {
+                counters.today = 0;
+            }
+            counters
+        };
+
+        RateLimitStats {
+            subjects: state
+                .subjects
+                .iter()
+                .map(|(subject, usage)| (subject.clone(), counters(usage)))
+                .collect(),
+            peers: state
+                .peers
+                .iter()
+                .map(|(peer, usage)| (peer.to_string(), counters(usage)))
+                .collect(),
+        }
+    }
+}
+
+impl State {
+    /// Forgets identities that made no request today and have none running,
+    /// once a day.
+    fn prune(&mut self, day: u64) {
+        if self.pruned == day {
+            return;
+        }
+        self.pruned = day;
+
+        let active = |usage: &Usage| usage.day == day || usage.counters.in_flight > 0;
+
+        self.subjects.retain(|_, usage| active(usage));
+        self.peers.retain(|_, usage| active(usage));
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if self.subject.is_none() && self.peer.is_none() {
+            return;
+        }
+
+        let mut state = RATE_LIMITER.state.lock().unwrap();
+
+        if let Some(usage) = self
+            .subject
+            .as_ref()
+            .and_then(|s| state.subjects.get_mut(s))
+        {
+            usage.counters.in_flight -= 1;
+        }
+
+        if let Some(usage) = self.peer.and_then(|peer| state.peers.get_mut(&peer)) {
+            usage.counters.in_flight -= 1;
+        }
+    }
+}
+
+/// Whom the limits of a session opened with `claims` from `peer` apply to:
+/// the subject of the token, or its id for tokens without one.
+///
+/// Tokens with neither share the limits of their address, or get limits of
+/// their own per session, never ones shared by every such token.
+pub fn subject(claims: &TokenClaims, peer: Option<IpAddr>) -> String {
+    match (&claims.sub, &claims.jti, peer) {
+        (Some(Value::String(sub)), _, _) => sub.clone(),
+        (Some(sub), _, _) => sub.to_string(),
+        (None, Some(jti), _) => format!("jti:{}", jti),
+        (None, None, Some(peer)) => format!("anonymous:{}", peer),
+        (None, None, None) => format!(
+            "anonymous#{}",
+            ANONYMOUS_SESSIONS.fetch_add(1, Ordering::Relaxed)
+        ),
+    }
+}
+
+fn rate_limited(message: &str, retry_after: Duration) -> ApiError {
+    ApiError::new(ErrorCode::RateLimit, message).with_retry_after(retry_after)
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn today() -> u64 {
+    now_secs() / DAY_SECS
+}
+
+fn until_tomorrow() -> Duration {
+    Duration::from_secs(DAY_SECS - now_secs() % DAY_SECS)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn bucket_starts_full() {
+        let mut bucket = Bucket::default();
+
+        for _ in 0..5 {
+            assert_eq!(bucket.wait(10.0, 5.0, 1.0), None);
+            bucket.take(10.0, 1.0);
+        }
+
+        // one token short, refilled at 10 per second
+        let wait = bucket.wait(10.0, 5.0, 1.0).unwrap().as_secs_f64();
+        assert!(wait > 0.09 && wait <= 0.1, "{}", wait);
+    }
+
+    #[test]
+    fn bucket_without_rate_never_waits() {
+        let mut bucket = Bucket::default();
+
+        for _ in 0..100 {
+            assert_eq!(bucket.wait(0.0, 0.0, 1.0), None);
+            bucket.take(0.0, 1.0);
+        }
+    }
+
+    #[test]
+    fn bucket_goes_into_debt_for_costs_over_the_burst() {
+        let mut bucket = Bucket::default();
+
+        // a full bucket is enough, however much the request costs
+        assert_eq!(bucket.wait(1.0, 5.0, 10.0), None);
+        bucket.take(1.0, 10.0);
+
+        // 5 tokens of debt and 1 for the next request
+        let wait = bucket.wait(1.0, 5.0, 1.0).unwrap().as_secs_f64();
+        assert!(wait > 5.9 && wait <= 6.0, "{}", wait);
+    }
+
+    #[test]
+    fn batches_cost_a_request_per_step() {
+        assert_eq!(cost(&json!({ "action": "Retrieve", "table": "users" })), 1);
+        assert_eq!(
+            cost(&json!({ "action": "Batch", "steps": [{}, {}, {}] })),
+            3
+        );
+        assert_eq!(cost(&json!({ "action": "Batch", "steps": [] })), 1);
+        assert_eq!(cost(&json!({ "action": "Insert", "steps": [{}, {}] })), 1);
+    }
+
+    #[test]
+    fn classifies_requests() {
+        assert_eq!(
+            Kind::of(&json!({ "action": "Subscribe" })),
+            Some(Kind::Read)
+        );
+        assert_eq!(
+            Kind::of(&json!({ "action": { "Delete": null } })),
+            Some(Kind::Write)
+        );
+        assert_eq!(Kind::of(&json!({ "action": "Commit" })), None);
+    }
+
+    #[test]
+    fn counts_tokens_without_subject_per_peer() {
+        let anonymous = TokenClaims::default();
+        let peer: IpAddr = "127.0.0.1".parse().unwrap();
+
+        assert_eq!(subject(&anonymous, Some(peer)), "anonymous:127.0.0.1");
+        assert_ne!(subject(&anonymous, None), subject(&anonymous, None));
+
+        let with_jti = TokenClaims {
+            jti: Some("abc".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(subject(&with_jti, Some(peer)), "jti:abc");
+
+        let with_sub = TokenClaims {
+            sub: Some(json!(42)),
+            ..with_jti
+        };
+        assert_eq!(subject(&with_sub, Some(peer)), "42");
+    }
+}
@@ -0,0 +1,292 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use sqlx::PgPool;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::Notify;
+use tokio::time::interval;
+
+use crate::auth::TokenClaims;
+use crate::config;
+use crate::db::changes::{Change, ChangeBus};
+use crate::db::Database;
+use crate::error::{ApiError, ErrorCode};
+
+/// Table of the tokens that were issued and not revoked yet.
+const TOKENS_TABLE: &str = "auth_tokens";
+
+lazy_static::lazy_static! {
+    static ref REVOCATIONS: Revocations = Revocations::default();
+}
+
+/// Whether the tokens with a `jti` are still listed in `auth_tokens`, and the
+/// live sessions that were opened with them.
+///
+/// A token is revoked by deleting its row. The answers are cached for
+/// `auth.revocation_cache_secs`, which is also how long a revoked token's
+/// sessions stay open at most.
+#[derive(Default)]
+struct Revocations {
+    cache: Mutex<HashMap<String, (bool, Instant)>>,
+    sessions: Mutex<HashMap<String, HashMap<u64, Arc<Notify>>>>,
+    next_session: AtomicU64,
+}
+
+/// Rejects a token whose `jti` is no longer listed in `auth_tokens`.
+///
+/// Tokens without a `jti` can't be revoked and are let through.
+pub async fn check(claims: &TokenClaims) -> Result<(), ApiError> {
+    if !config::get().auth.revocation {
+        return Ok(());
+    }
+
+    let Some(jti) = &claims.jti else {
+        return Ok(());
+    };
+
+    if let Some(valid) = REVOCATIONS.cached(jti) {
+        return valid_or_revoked(valid);
+    }
+
+    let valid = async {
+        let pool = Database::get_pool().await?;
+        let valid = valid_jtis(&pool, &[jti.clone()]).await?;
+        anyhow::Ok(valid.contains(jti))
+    }
+    .await
+    .map_err(|e| {
+        eprintln!("error: failed to check token revocation: {}", e);
+        ApiError::internal()
+    })?;
+
+    REVOCATIONS.store(jti, valid);
+    valid_or_revoked(valid)
+}
+
+fn valid_or_revoked(valid: bool) -> Result<(), ApiError> {
+    if valid {
+        Ok(())
+    } else {
+        Err(ApiError::new(ErrorCode::Auth, "token has been revoked."))
+    }
+}
+
+/// Registration of a live session under the `jti` of its token, removed again
+/// once it is dropped.
+pub struct SessionWatch {
+    jti: Option<String>,
+    id: u64,
+    revoked: Arc<Notify>,
+}
+
+impl SessionWatch {
+    pub fn new(claims: &TokenClaims) -> Self {
+        let id = REVOCATIONS.next_session.fetch_add(1, Ordering::Relaxed);
+        let revoked = Arc::new(Notify::new());
+
+        if let Some(jti) = &claims.jti {
+            let mut sessions = REVOCATIONS.sessions.lock().unwrap();
+            sessions
+                .entry(jti.clone())
+                .or_default()
+                .insert(id, revoked.clone());
+        }
+
+        Self {
+            jti: claims.jti.clone(),
+            id,
+            revoked,
+        }
+    }
+
+    /// Resolves once the token of the session is revoked, never for tokens
+    /// without a `jti`.
+    pub async fn revoked(&self) {
+        self.revoked.notified().await
+    }
+}
+
+impl Drop for SessionWatch {
+    fn drop(&mut self) {
+        let Some(jti) = &self.jti else {
+            return;
+        };
+
+        let mut sessions = REVOCATIONS.sessions.lock().unwrap();
+
+        if let Some(watches) = sessions.get_mut(jti) {
+            watches.remove(&self.id);
+
+            if watches.is_empty() {
+                sessions.remove(jti);
+            }
+        }
+    }
+}
+
+/// Closes the sessions of revoked tokens until the process exits: right away
+/// when `auth_tokens` changes, and every `auth.revocation_cache_secs` for
+/// changes nobody told us about.
+pub async fn run() {
+    let auth_config = &config::get().auth;
+
+    if !auth_config.revocation {
+        return;
+    }
+
+    let mut changes = ChangeBus::subscribe_tables();
+    let mut sweep = interval(Duration::from_secs(
+        auth_config.revocation_cache_secs.max(1),
+    ));
+
+    loop {
+        tokio::select! {
+            change = changes.recv() => match change {
+                Ok(change) => match change.as_ref() {
+                    Change::Table { table } if table == TOKENS_TABLE => {}
+                    Change::All => {}
+                    _ => continue,
+                },
+                Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => return,
+            },
+            _ = sweep.tick() => {}
+        }
+
+        if let Err(e) = REVOCATIONS.sweep().await {
+            eprintln!("error: failed to check live tokens for revocation: {}", e);
+        }
+    }
+}
+
+impl Revocations {
+    fn cached(&self, jti: &str) -> Option<bool> {
+        let ttl = Duration::from_secs(config::get().auth.revocation_cache_secs);
+        let cache = self.cache.lock().unwrap();
+
+        cache
+            .get(jti)
+            .filter(|(_, checked)| checked.elapsed() < ttl)
+            .map(|(valid, _)| *valid)
+    }
+
+    fn store(&self, jti: &str, valid: bool) {
+        let ttl = Duration::from_secs(config::get().auth.revocation_cache_secs);
+        let mut cache = self.cache.lock().unwrap();
+
+        cache.retain(|_, (_, checked)| checked.elapsed() < ttl);
+        cache.insert(jti.to_string(), (valid, Instant::now()));
+    }
+
+    /// Checks the tokens of all live sessions, closes the revoked ones.
+    async fn sweep(&self) -> Result<()> {
+        let live: Vec<String> = self.sessions.lock().unwrap().keys().cloned().collect();
+
+        if live.is_empty() {
+            return Ok(());
+        }
+
+        let pool = Database::get_pool().await?;
+        let valid = valid_jtis(&pool, &live).await?;
+
+        for jti in &live {
+            self.store(jti, valid.contains(jti));
+        }
+
+        let sessions = self.sessions.lock().unwrap();
+
+        for jti in live.iter().filter(|jti| !valid.contains(*jti)) {
+            for revoked in sessions.get(jti).into_iter().flat_map(HashMap::values) {
+                revoked.notify_one();
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns which of `jtis` are listed in `auth_tokens` and not expired.
+async fn valid_jtis(pool: &PgPool, jtis: &[String]) -> Result<HashSet<String>> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64;
+
+    let valid: Vec<String> = sqlx::query_scalar(&format!(
+        "SELECT jti FROM {} WHERE jti = ANY($1) AND expires_at > $2",
+        TOKENS_TABLE
+    ))
+    .bind(jtis)
+    .bind(now)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(valid.into_iter().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn caches_answers() {
+        let revocations = Revocations::default();
+        assert_eq!(revocations.cached("a"), None);
+
+        revocations.store("a", true);
+        revocations.store("b", false);
+
+        assert_eq!(revocations.cached("a"), Some(true));
+        assert_eq!(revocations.cached("b"), Some(false));
+        assert_eq!(revocations.cached("c"), None);
+    }
+
+    #[tokio::test]
+    async fn rejects_revoked_tokens_and_lets_tokens_without_jti_through() {
+        check(&TokenClaims::default()).await.unwrap();
+
+        // answered from the cache, without asking the database
+        REVOCATIONS.store("revoked-before-check", false);
+        let claims: TokenClaims =
+            serde_json::from_value(json!({ "jti": "revoked-before-check" })).unwrap();
+
+        let error = check(&claims).await.unwrap_err();
+        assert_eq!(error.code, ErrorCode::Auth);
+    }
+
+    #[tokio::test]
+    async fn wakes_and_unregisters_the_sessions_of_a_token() {
+        let claims: TokenClaims =
+            serde_json::from_value(json!({ "jti": "revoked-while-open" })).unwrap();
+
+        let first = SessionWatch::new(&claims);
+        let second = SessionWatch::new(&claims);
+        let unrevocable = SessionWatch::new(&TokenClaims::default());
+
+        for revoked in REVOCATIONS.sessions.lock().unwrap()["revoked-while-open"].values() {
+            revoked.notify_one();
+        }
+
+        first.revoked().await;
+        second.revoked().await;
+
+        drop(first);
+        assert_eq!(
+            REVOCATIONS.sessions.lock().unwrap()["revoked-while-open"].len(),
+            1
+        );
+
+        drop(second);
+        drop(unrevocable);
+        assert!(!REVOCATIONS
+            .sessions
+            .lock()
+            .unwrap()
+            .contains_key("revoked-while-open"));
+    }
+}
@@ -0,0 +1,255 @@
+use std::io::{Read, Write};
+
+use actix_web::web::Bytes;
+use actix_ws::{CloseCode, CloseReason};
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+
+use super::close_reason;
+use crate::config::WebSocketConfig;
+use crate::protocol::{Codec, JSON_PROTOCOL, MSGPACK_DEFLATE_PROTOCOL, MSGPACK_PROTOCOL};
+
+/// First byte of a binary frame of a deflate session, whether the rest of it
+/// is compressed.
+const RAW: u8 = 0;
+const DEFLATED: u8 = 1;
+
+/// Subprotocols the server confirms, the first one the client offers wins.
+const PROTOCOLS: [&str; 3] = [MSGPACK_DEFLATE_PROTOCOL, MSGPACK_PROTOCOL, JSON_PROTOCOL];
+
+/// A message ready to be sent.
+pub enum Frame {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+/// How the messages of a session are put into websocket frames.
+///
+/// Text frames always carry json, uncompressed. Binary frames carry
+/// messagepack, on `a4s.msgpack+deflate` behind a flag byte that tells whether
+/// the rest of the frame is deflated. Messages of at least
+/// `websocket.compress_min_bytes` are sent deflated, smaller ones as they are.
+///
+/// This is not `permessage-deflate`: actix-ws can't set the RSV1 bit the
+/// extension needs, so compression is an opt-in subprotocol of the api.
+#[derive(Debug, Clone, Copy)]
+pub struct Framing {
+    /// encoding of binary frames, `None` if the session may not send any
+    binary: Option<Codec>,
+    deflate: bool,
+    min_bytes: usize,
+    level: u32,
+    /// largest message a deflated frame may inflate to
+    max_size: usize,
+}
+
+impl Framing {
+    /// Picks the framing from the subprotocols the client offered, returns it
+    /// with the subprotocol to confirm. `msgpack` is the `?encoding=msgpack`
+    /// fallback for clients that can't set subprotocols.
+    pub fn negotiate(
+        offered: &[&str],
+        msgpack: bool,
+        websocket_config: &WebSocketConfig,
+    ) -> (Self, Option<&'static str>) {
+        let protocol = PROTOCOLS
+            .into_iter()
+            .find(|protocol| offered.contains(protocol));
+
+        let (binary, deflate) = match protocol {
+            Some(MSGPACK_DEFLATE_PROTOCOL) => (Some(Codec::MessagePack), true),
+            Some(MSGPACK_PROTOCOL) => (Some(Codec::MessagePack), false),
+            _ if msgpack => (Some(Codec::MessagePack), false),
+            _ => (None, false),
+        };
+
+        let framing = Self {
+            binary,
+            deflate,
+            min_bytes: websocket_config.compress_min_bytes,
+            level: websocket_config.compress_level.min(9),
+            max_size: websocket_config.max_message_size,
+        };
+
+        (framing, protocol)
+    }
+
+    /// Reads a binary frame, returns the encoding and the bytes of the message
+    /// or the reason to close the session with.
+    pub fn decode_binary(&self, frame: Bytes) -> Result<(Codec, Bytes), CloseReason> {
+        let Some(codec) = self.binary else {
+            return Err(close_reason(
+                CloseCode::Unsupported,
+                "binary messages need the a4s.msgpack subprotocol",
+            ));
+        };
+
+        if !self.deflate {
+            return Ok((codec, frame));
+        }
+
+        match frame.first() {
+            Some(&RAW) => Ok((codec, frame.slice(1..))),
+            Some(&DEFLATED) => {
+                let message = inflate(&frame[1..], self.max_size)?;
+                Ok((codec, Bytes::from(message)))
+            }
+            _ => Err(close_reason(CloseCode::Invalid, "unknown frame flag")),
+        }
+    }
+
+    /// Puts an encoded message into the frame it is sent in.
+    pub fn encode(&self, codec: Codec, payload: Vec<u8>) -> Frame {
+        if codec == Codec::MessagePack && self.deflate {
+            if payload.len() >= self.min_bytes {
+                if let Ok(frame) = deflate(&payload, self.level) {
+                    return Frame::Binary(frame);
+                }
+            }
+
+            let mut frame = Vec::with_capacity(payload.len() + 1);
+            frame.push(RAW);
+            frame.extend_from_slice(&payload);
+            return Frame::Binary(frame);
+        }
+
+        match codec {
+            // json output is always valid utf-8
+            Codec::Json => Frame::Text(String::from_utf8(payload).unwrap_or_default()),
+            Codec::MessagePack => Frame::Binary(payload),
+        }
+    }
+}
+
+/// Deflates `payload` into a frame with the `DEFLATED` flag.
+fn deflate(payload: &[u8], level: u32) -> std::io::Result<Vec<u8>> {
+    let mut encoder = DeflateEncoder::new(vec![DEFLATED], Compression::new(level));
+    encoder.write_all(payload)?;
+    encoder.finish()
+}
+
+/// Inflates a message, refusing to produce more than `max_size` bytes.
+fn inflate(data: &[u8], max_size: usize) -> Result<Vec<u8>, CloseReason> {
+    let mut message = Vec::new();
+
+    DeflateDecoder::new(data)
+        .take(max_size as u64 + 1)
+        .read_to_end(&mut message)
+        .map_err(|_| close_reason(CloseCode::Invalid, "malformed deflated message"))?;
+
+    if message.len() > max_size {
+        return Err(close_reason(CloseCode::Size, "message too big"));
+    }
+
+    Ok(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use acid4sigmas_models::models::db::DatabaseResponse;
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    /// The reply to a retrieve of 100 rows of `users`.
+    fn users_retrieve(codec: Codec) -> Vec<u8> {
+        let rows: Vec<Value> = (0..100u64)
+            .map(|i| {
+                json!({
+                    "uid": 3243294239u64 + i,
+                    "username": format!("user{}", i),
+                    "email": format!("user{}@example.com", i),
+                    "email_verified": i % 3 != 0,
+                    "owner": false,
+                    "created_at": format!("2024-10-{:02}T12:00:00Z", i % 28 + 1),
+                })
+            })
+            .collect();
+
+        let reply = Ok(DatabaseResponse::Data(rows));
+        codec
+            .encode_reply(Some(&json!("load-users")), &reply)
+            .unwrap()
+    }
+
+    #[test]
+    fn compresses_users_retrieve() {
+        let config = WebSocketConfig::default();
+        let (framing, _) = Framing::negotiate(&[MSGPACK_DEFLATE_PROTOCOL], false, &config);
+        let payload = users_retrieve(Codec::MessagePack);
+
+        let Frame::Binary(frame) = framing.encode(Codec::MessagePack, payload.clone()) else {
+            panic!("messagepack is sent in binary frames");
+        };
+
+        let ratio = frame.len() as f64 / payload.len() as f64;
+        assert_eq!(frame[0], DEFLATED);
+        assert!(ratio < 0.35, "compressed to {:.0}%", ratio * 100.0);
+
+        let (codec, message) = framing.decode_binary(Bytes::from(frame)).unwrap();
+        assert_eq!(codec, Codec::MessagePack);
+        assert_eq!(&message[..], &payload[..]);
+    }
+
+    #[test]
+    fn keeps_json_and_small_messages_uncompressed() {
+        let config = WebSocketConfig::default();
+        let (framing, _) = Framing::negotiate(&[MSGPACK_DEFLATE_PROTOCOL], false, &config);
+
+        // json always goes into text frames, however big
+        let payload = users_retrieve(Codec::Json);
+        let Frame::Text(text) = framing.encode(Codec::Json, payload.clone()) else {
+            panic!("json is sent in text frames");
+        };
+        assert_eq!(text.as_bytes(), &payload[..]);
+
+        let Frame::Binary(frame) = framing.encode(Codec::MessagePack, vec![0x80]) else {
+            panic!("messagepack is sent in binary frames");
+        };
+        assert_eq!(frame, [RAW, 0x80]);
+    }
+
+    #[test]
+    fn rejects_oversized_and_malformed_frames() {
+        let config = WebSocketConfig {
+            max_message_size: 1024,
+            ..Default::default()
+        };
+        let (framing, _) = Framing::negotiate(&[MSGPACK_DEFLATE_PROTOCOL], false, &config);
+
+        let bomb = deflate(&vec![b' '; 1024 * 1024], 9).unwrap();
+        let error = framing.decode_binary(Bytes::from(bomb)).unwrap_err();
+        assert_eq!(error.code, CloseCode::Size);
+
+        let error = framing
+            .decode_binary(Bytes::from_static(&[2, 0]))
+            .unwrap_err();
+        assert_eq!(error.code, CloseCode::Invalid);
+
+        let error = framing
+            .decode_binary(Bytes::from_static(&[DEFLATED, 0xff, 0xff]))
+            .unwrap_err();
+        assert_eq!(error.code, CloseCode::Invalid);
+    }
+
+    #[test]
+    fn negotiates_preferred_protocol() {
+        let config = WebSocketConfig::default();
+
+        let (framing, protocol) =
+            Framing::negotiate(&["a4s.json", "a4s.msgpack+deflate"], false, &config);
+        assert_eq!(protocol, Some(MSGPACK_DEFLATE_PROTOCOL));
+        assert_eq!(framing.binary, Some(Codec::MessagePack));
+        assert!(framing.deflate);
+
+        let (framing, protocol) = Framing::negotiate(&["a4s.json+deflate"], false, &config);
+        assert_eq!(protocol, None);
+        assert_eq!(framing.binary, None);
+
+        let (framing, protocol) = Framing::negotiate(&[], true, &config);
+        assert_eq!(protocol, None);
+        assert_eq!(framing.binary, Some(Codec::MessagePack));
+        assert!(!framing.deflate);
+    }
+}
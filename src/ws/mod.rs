@@ -0,0 +1,487 @@
+use std::io;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use acid4sigmas_models::error_response;
+use acid4sigmas_models::models::db::{DatabaseRequest, DatabaseResponse};
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::{rt, web, Error, HttpRequest, HttpResponse};
+use actix_ws::{
+    AggregatedMessage, AggregatedMessageStream, CloseCode, CloseReason, ProtocolError, Session,
+};
+use futures_util::StreamExt as _;
+use serde::Deserialize;
+use tokio::sync::Semaphore;
+use tokio::time::{interval, sleep_until, Duration, Instant, MissedTickBehavior};
+
+use crate::auth::{self, TokenClaims};
+use crate::config;
+use crate::db::batch::{Batch, BatchRequest};
+use crate::db::db_handler::{DatabaseHandler, DbHandler};
+use crate::db::transaction::Scope;
+use crate::db::Database;
+use crate::error::ApiError;
+use crate::guards;
+use crate::policy::{self, Mask};
+use crate::protocol::{self, ClientRequest, Codec, Reply, RequestId, TOKEN_PROTOCOL_PREFIX};
+use crate::rate_limit::{self, Kind, RATE_LIMITER};
+use crate::revocation::{self, SessionWatch};
+
+mod framing;
+mod sender;
+mod subscriptions;
+mod transactions;
+
+use framing::Framing;
+use sender::ResponseSender;
+use subscriptions::{SubscriptionRequest, Subscriptions};
+use transactions::{TransactionRequest, Transactions};
+
+pub async fn db_ws(req: HttpRequest, stream: web::Payload) -> Result<HttpResponse, Error> {
+    let query = req.query_string();
+
+    let query = match web::Query::<std::collections::HashMap<String, String>>::from_query(query) {
+        Ok(query) => query.into_inner(),
+        Err(e) => return Ok(error_response!(400, e.to_string())),
+    };
+
+    // browsers can't set headers on a websocket, they send the token as a subprotocol
+    let token = auth::bearer_token(&req)
+        .or_else(|| requested_protocols(&req).find_map(|p| p.strip_prefix(TOKEN_PROTOCOL_PREFIX)));
+
+    let token = match (token, query.get("token").map(String::as_str)) {
+        (Some(token), _) => token,
+        (None, Some(token)) if config::get().auth.query_token => token,
+        (None, Some(_)) => {
+            return Ok(error_response!(
+                403,
+                "tokens in the query string are disabled, use the Authorization header."
+            ))
+        }
+        (None, None) => return Ok(error_response!(403, "no token found.")),
+    };
+
+    let claims = match auth::verify_backend_token(token) {
+        Ok(claims) => claims,
+        Err(e) => return Ok(e.to_http_response()),
+    };
+
+    if let Err(e) = revocation::check(&claims).await {
+        return Ok(e.to_http_response());
+    }
+
+    // closes the session once its token is revoked
+    let revocation = SessionWatch::new(&claims);
+
+    // the session may not outlive the token it was opened with
+    let expires_at = claims
+        .expires_in()
+        .map(|expires_in| Instant::now() + expires_in);
+
+    let websocket_config = &config::get().websocket;
+
+    // messagepack and compression are negotiated through the subprotocol, or
+    // `?encoding=msgpack` for clients that can't set it. the token protocol
+    // is never confirmed, it would echo the token
+    let offered: Vec<&str> = requested_protocols(&req).collect();
+    let msgpack = query.get("encoding").is_some_and(|e| e == "msgpack");
+    let (framing, confirmed_protocol) = Framing::negotiate(&offered, msgpack, websocket_config);
+
+    let (mut res, session, stream) = actix_ws::handle(&req, stream)?;
+    let sender = ResponseSender::new(session.clone(), framing);
+    let peer = req.peer_addr().map(|addr| addr.ip());
+    let context = SessionContext {
+        subject: rate_limit::subject(&claims, peer).into(),
+        claims: Arc::new(claims),
+        peer,
+        subscriptions: Subscriptions::new(sender.clone()),
+        transactions: Transactions::default(),
+    };
+
+    if let Some(protocol) = confirmed_protocol {
+        res.headers_mut().insert(
+            header::SEC_WEBSOCKET_PROTOCOL,
+            HeaderValue::from_static(protocol),
+        );
+    }
+
+    if let Some(peer_addr) = req.peer_addr() {
+        println!("New WebSocket connection established from: {}", peer_addr);
+    } else {
+        println!("New WebSocket connection established");
+    }
+
+    let stream = stream
+        .max_frame_size(websocket_config.max_frame_size)
+        .aggregate_continuations()
+        .max_continuation_size(websocket_config.max_message_size);
+
+    rt::spawn(async move {
+        let reason = run_session(
+            session.clone(),
+            sender,
+            context.clone(),
+            stream,
+            framing,
+            expires_at,
+            &revocation,
+        )
+        .await;
+        context.close().await;
+
+        if let Some(reason) = &reason {
+            println!("Closing WebSocket session: {:?}", reason);
+        }
+
+        // fails only if the session is already closed, nothing left to do then
+        let _ = session.close(reason).await;
+    });
+
+    Ok(res)
+}
+
+/// State shared by all requests of a session.
+#[derive(Clone)]
+struct SessionContext {
+    /// claims of the token the session was opened with
+    claims: Arc<TokenClaims>,
+    /// whom the rate limits of the session's requests are counted for
+    subject: Arc<str>,
+    /// address of the client, for the rate limits
+    peer: Option<IpAddr>,
+    subscriptions: Subscriptions,
+    transactions: Transactions,
+}
+
+impl SessionContext {
+    /// Ends everything the session left open.
+    async fn close(&self) {
+        self.subscriptions.close().await;
+        self.transactions.close().await;
+    }
+}
+
+/// Reads and answers messages until the session ends, returns the reason to
+/// close the session with.
+///
+/// Besides answering requests this pings the client regularly, and ends the
+/// session once the client stops responding, `expires_at` is reached or its
+/// token is revoked.
+async fn run_session(
+    mut session: Session,
+    sender: ResponseSender,
+    context: SessionContext,
+    mut stream: AggregatedMessageStream,
+    framing: Framing,
+    expires_at: Option<Instant>,
+    revocation: &SessionWatch,
+) -> Option<CloseReason> {
+    let websocket_config = &config::get().websocket;
+
+    // limits how many requests of this session run at the same time, once it
+    // is reached no further frames are read until one of them finishes
+    let max_in_flight = websocket_config.max_in_flight.max(1);
+    let in_flight = Arc::new(Semaphore::new(max_in_flight));
+
+    let mut heartbeat = interval(Duration::from_secs(
+        websocket_config.ping_interval_secs.max(1),
+    ));
+    heartbeat.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let idle_timeout = Duration::from_secs(websocket_config.idle_timeout_secs);
+    let mut last_activity = Instant::now();
+
+    let token_expiry = sleep_until(expires_at.unwrap_or_else(Instant::now));
+    tokio::pin!(token_expiry);
+
+    loop {
+        let msg = tokio::select! {
+            msg = stream.next() => msg,
+
+            _ = heartbeat.tick() => {
+                if last_activity.elapsed() > idle_timeout {
+                    return Some(close_reason(CloseCode::Policy, "idle timeout"));
+                }
+
+                if session.ping(b"").await.is_err() {
+                    return None;
+                }
+
+                continue;
+            }
+
+            _ = &mut token_expiry, if expires_at.is_some() => {
+                return Some(close_reason(CloseCode::Policy, "token expired"));
+            }
+
+            _ = revocation.revoked() => {
+                return Some(close_reason(CloseCode::Policy, "token revoked"));
+            }
+        };
+
+        // the client closed the connection
+        let msg = msg?;
+
+        // any frame, including pongs to our pings, shows the client is alive
+        last_activity = Instant::now();
+
+        let (codec, payload) = match msg {
+            Ok(AggregatedMessage::Text(text)) => (Codec::Json, text.into_bytes()),
+
+            Ok(AggregatedMessage::Binary(bin)) => match framing.decode_binary(bin) {
+                Ok(message) => message,
+                Err(reason) => return Some(reason),
+            },
+
+            Ok(AggregatedMessage::Ping(msg)) => {
+                println!("heartbeat received");
+
+                if session.pong(&msg).await.is_err() {
+                    return None;
+                }
+
+                continue;
+            }
+
+            Ok(AggregatedMessage::Pong(msg)) => {
+                println!("Received Pong Message: {:?}", msg);
+                continue;
+            }
+
+            Ok(AggregatedMessage::Close(reason)) => {
+                println!("Received Close Message with reason: {:?}", reason);
+                return Some(CloseCode::Normal.into()); // Exit the loop on close
+            }
+
+            Err(e) => {
+                println!("Error while processing message: {:?}", e);
+                return Some(protocol_error_reason(&e));
+            }
+        };
+
+        let Ok(permit) = in_flight.clone().acquire_owned().await else {
+            return Some(close_reason(CloseCode::Error, "internal server error"));
+        };
+        let mut sender = sender.clone();
+        let context = context.clone();
+
+        // responses are sent as soon as they are ready, clients match
+        // them to their requests by id
+        rt::spawn(async move {
+            let (id, reply) = handle_message(codec, &payload, &context).await;
+
+            // a failed send means the client is gone, the session loop
+            // notices that on its own
+            let _ = sender.send(codec, id.as_ref(), &reply).await;
+            drop(permit);
+        });
+    }
+}
+
+/// Subprotocols the client asked for in its handshake.
+fn requested_protocols(req: &HttpRequest) -> impl Iterator<Item = &str> {
+    req.headers()
+        .get_all(header::SEC_WEBSOCKET_PROTOCOL)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+}
+
+fn close_reason(code: CloseCode, description: &str) -> CloseReason {
+    CloseReason {
+        code,
+        description: Some(description.to_string()),
+    }
+}
+
+/// Picks the close code for a frame the stream could not accept.
+fn protocol_error_reason(error: &ProtocolError) -> CloseReason {
+    match error {
+        ProtocolError::Overflow => close_reason(CloseCode::Size, "message too big"),
+        ProtocolError::Io(e) if e.kind() == io::ErrorKind::InvalidData => {
+            close_reason(CloseCode::Invalid, "text message is not valid utf-8")
+        }
+        // the aggregated stream reports oversized continuations as a plain io error
+        ProtocolError::Io(e) if e.to_string().contains("maximum continuation size") => {
+            close_reason(CloseCode::Size, "message too big")
+        }
+        ProtocolError::Io(_) => close_reason(CloseCode::Error, "connection error"),
+        _ => close_reason(CloseCode::Protocol, &error.to_string()),
+    }
+}
+
+/// Runs a single request and returns its reply, together with the id the
+/// client tagged the request with.
+async fn handle_message(
+    codec: Codec,
+    payload: &[u8],
+    context: &SessionContext,
+) -> (Option<RequestId>, Reply) {
+    let mut value = match codec.decode(payload) {
+        Ok(value) => value,
+        Err(e) => {
+            let error = ApiError::validation(format!("Failed to parse request: {}", e));
+            return (None, Err(error));
+        }
+    };
+
+    // read the id first, so it can be echoed even if the rest of the request is invalid
+    let id = protocol::request_id(&value);
+
+    // held until the request finished, it counts as running until then
+    let _permit = match Kind::of(&value) {
+        Some(kind) => {
+            match RATE_LIMITER.acquire(
+                &context.subject,
+                &context.claims,
+                context.peer,
+                kind,
+                rate_limit::cost(&value),
+            ) {
+                Ok(permit) => Some(permit),
+                Err(e) => return (id, Err(e)),
+            }
+        }
+        None => None,
+    };
+
+    let policy = policy::get();
+
+    if SubscriptionRequest::is_subscription(&value) {
+        // the row rules of the policy end up in the filters of the subscription
+        let mut mask = Mask::default();
+        if value.get("action").and_then(serde_json::Value::as_str) == Some("Subscribe") {
+            if let Err(e) = guards::apply(&mut value) {
+                return (id, Err(e));
+            }
+
+            match policy.authorize(&context.claims, &mut value) {
+                Ok(subscription_mask) => mask = subscription_mask,
+                Err(e) => return (id, Err(e)),
+            }
+        }
+
+        let reply = match SubscriptionRequest::deserialize(&value) {
+            Ok(request) => {
+                context
+                    .subscriptions
+                    .handle(codec, id.as_ref(), request, mask)
+                    .await
+            }
+            Err(e) => Err(ApiError::validation(format!(
+                "Failed to parse request: {}",
+                e
+            ))),
+        };
+
+        return (id, reply);
+    }
+
+    if BatchRequest::is_batch(&value) {
+        let reply = match serde_json::from_value::<BatchRequest>(value) {
+            Ok(request) => run_batch(request, context).await,
+            Err(e) => Err(ApiError::validation(format!(
+                "Failed to parse request: {}",
+                e
+            ))),
+        };
+
+        return (id, reply);
+    }
+
+    if TransactionRequest::is_transaction(&value) {
+        let reply = match serde_json::from_value::<TransactionRequest>(value) {
+            Ok(request) => context.transactions.handle(id.as_ref(), request).await,
+            Err(e) => Err(ApiError::validation(format!(
+                "Failed to parse request: {}",
+                e
+            ))),
+        };
+
+        return (id, reply);
+    }
+
+    let client_request = match ClientRequest::deserialize(&value) {
+        Ok(client_request) => client_request,
+        Err(e) => {
+            let error = ApiError::validation(format!("Failed to parse request: {}", e));
+            return (id, Err(error));
+        }
+    };
+
+    let cache_mode = client_request.cache_mode();
+
+    let transaction = match &client_request.transaction {
+        Some(transaction) => match context.transactions.get(transaction).await {
+            Ok(transaction) => Some(transaction),
+            Err(e) => return (id, Err(e)),
+        },
+        None => None,
+    };
+
+    // the guards see the filters of the client, a where filter the policy
+    // adds does not count as one
+    if let Err(e) = guards::apply(&mut value) {
+        return (id, Err(e));
+    }
+
+    let mask = match policy.authorize(&context.claims, &mut value) {
+        Ok(mask) => mask,
+        Err(e) => return (id, Err(e)),
+    };
+
+    // the guards may have limited the request to a number of rows, and the
+    // policy to the rows the token owns
+    let mut request = match DatabaseRequest::deserialize(&value) {
+        Ok(request) => request,
+        Err(e) => {
+            let error = ApiError::validation(format!("Failed to parse request: {}", e));
+            return (id, Err(error));
+        }
+    };
+
+    if let Err(e) = request.validate() {
+        return (id, Err(ApiError::validation(e.to_string())));
+    }
+
+    let db_handler = match DatabaseHandler::new(request, cache_mode, transaction).await {
+        Ok(db_handler) => db_handler,
+        Err(e) => return (id, Err(ApiError::from(e))),
+    };
+
+    // masked here rather than in the handler, so cached rows are masked too
+    let reply = db_handler
+        .handle_request()
+        .await
+        .map(|mut response| {
+            if let DatabaseResponse::Data(rows) = &mut response {
+                mask.apply(rows);
+            }
+            response
+        })
+        .map_err(ApiError::from);
+
+    (id, reply)
+}
+
+/// Runs all steps of a batch in one transaction, replies with the rows each
+/// step wrote.
+async fn run_batch(request: BatchRequest, context: &SessionContext) -> Reply {
+    let transaction = match &request.transaction {
+        Some(transaction) => Some(context.transactions.get(transaction).await?),
+        None => None,
+    };
+
+    let result = async {
+        let pool = Database::get_pool().await?;
+
+        // every step is authorized by the batch once its references are resolved
+        let mut scope = Scope::begin(&pool, transaction.as_ref(), "Batch").await?;
+        let (results, changes) = Batch::run(scope.conn(), request.steps, &context.claims).await?;
+        scope.finish_all(changes).await?;
+
+        anyhow::Ok(DatabaseResponse::Data(results))
+    }
+    .await;
+
+    result.map_err(ApiError::from)
+}
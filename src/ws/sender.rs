@@ -0,0 +1,61 @@
+use actix_ws::{Closed, Session};
+use serde_json::Value;
+
+use super::framing::{Frame, Framing};
+use crate::error::ApiError;
+use crate::protocol::{Codec, Reply, RequestId};
+
+/// Sending half of a websocket session, shared by every request the session
+/// is processing at the same time.
+///
+/// Cloning it is cheap, all clones write into the same session.
+#[derive(Clone)]
+pub struct ResponseSender {
+    session: Session,
+    framing: Framing,
+}
+
+impl ResponseSender {
+    pub fn new(session: Session, framing: Framing) -> Self {
+        Self { session, framing }
+    }
+
+    /// Sends the reply to the request tagged with `id`, encoded with the codec
+    /// the request came in with.
+    pub async fn send(
+        &mut self,
+        codec: Codec,
+        id: Option<&RequestId>,
+        reply: &Reply,
+    ) -> Result<(), Closed> {
+        // Serialize the reply and send it
+        let payload = match codec.encode_reply(id, reply) {
+            Ok(payload) => payload,
+            Err(e) => {
+                eprintln!("error: failed to serialize reply: {}", e);
+                codec
+                    .encode_reply(id, &Err(ApiError::internal()))
+                    .map_err(|_| Closed)?
+            }
+        };
+
+        self.send_payload(codec, payload).await
+    }
+
+    /// Sends a message that does not answer a request, like subscription events.
+    pub async fn send_value(&mut self, codec: Codec, value: &Value) -> Result<(), Closed> {
+        let payload = codec.encode(value).map_err(|e| {
+            eprintln!("error: failed to serialize message: {}", e);
+            Closed
+        })?;
+
+        self.send_payload(codec, payload).await
+    }
+
+    async fn send_payload(&mut self, codec: Codec, payload: Vec<u8>) -> Result<(), Closed> {
+        match self.framing.encode(codec, payload) {
+            Frame::Text(text) => self.session.text(text).await,
+            Frame::Binary(frame) => self.session.binary(frame).await,
+        }
+    }
+}
@@ -0,0 +1,469 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use acid4sigmas_models::models::db::{DatabaseResponse, Filters};
+use actix_web::rt;
+use actix_ws::Closed;
+use anyhow::Result;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+
+use super::sender::ResponseSender;
+use crate::cache::CacheMode;
+use crate::config;
+use crate::db::changes::{Change, ChangeBus, ChangeOp};
+use crate::db::retrieve::Retrieve;
+use crate::db::table::Table;
+use crate::db::Database;
+use crate::error::{ApiError, ErrorCode};
+use crate::policy::Mask;
+use crate::protocol::{Codec, Reply, RequestId};
+
+/// Subscription actions, handled by the session itself instead of the
+/// `DatabaseHandler`.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "action")]
+pub enum SubscriptionRequest {
+    Subscribe {
+        table: String,
+        #[serde(default)]
+        filters: Option<Value>,
+    },
+    Unsubscribe {
+        subscription: RequestId,
+    },
+}
+
+impl SubscriptionRequest {
+    /// Whether the raw request is one of the subscription actions.
+    pub fn is_subscription(value: &Value) -> bool {
+        matches!(
+            value.get("action").and_then(Value::as_str),
+            Some("Subscribe" | "Unsubscribe")
+        )
+    }
+}
+
+struct Subscription {
+    /// id of the request that created the subscription, tags all its events
+    id: RequestId,
+    codec: Codec,
+    table: String,
+    filters: Option<Filters>,
+    /// the `where` of the filters, rows have to match all of them
+    conditions: Map<String, Value>,
+    /// columns of the rows the session's token may not see
+    mask: Mask,
+    /// primary key columns, empty if the table has none or the token may not
+    /// see all of them
+    key: Vec<String>,
+    /// keys of the rows the client was sent and holds, as json
+    held: HashSet<String>,
+    /// changes that arrived while the snapshot was loaded, `None` once the
+    /// snapshot was sent
+    pending: Option<Vec<Arc<Change>>>,
+}
+
+#[derive(Default)]
+struct Registry {
+    entries: HashMap<String, Subscription>,
+    forwarder: Option<JoinHandle<()>>,
+    closed: bool,
+}
+
+/// Live subscriptions of a single session.
+///
+/// A subscription sends a snapshot of the matching rows first, then an event
+/// for every insert, update and delete of matching rows. Cloning it is cheap,
+/// all clones share the same subscriptions.
+#[derive(Clone)]
+pub struct Subscriptions {
+    registry: Arc<Mutex<Registry>>,
+    sender: ResponseSender,
+}
+
+impl Subscriptions {
+    pub fn new(sender: ResponseSender) -> Self {
+        Self {
+            registry: Arc::new(Mutex::new(Registry::default())),
+            sender,
+        }
+    }
+
+    pub async fn handle(
+        &self,
+        codec: Codec,
+        id: Option<&RequestId>,
+        request: SubscriptionRequest,
+        mask: Mask,
+    ) -> Reply {
+        match request {
+            SubscriptionRequest::Subscribe { table, filters } => {
+                let id = id.ok_or_else(|| {
+                    ApiError::validation("Subscribe needs an id to tag its events with.")
+                })?;
+
+                self.subscribe(codec, id, table, filters, mask)
+                    .await
+                    .map_err(ApiError::from)
+            }
+            SubscriptionRequest::Unsubscribe { subscription } => {
+                self.unsubscribe(&subscription).await
+            }
+        }
+    }
+
+    async fn subscribe(
+        &self,
+        codec: Codec,
+        id: &RequestId,
+        table: String,
+        filters: Option<Value>,
+        mask: Mask,
+    ) -> Result<DatabaseResponse<Value>> {
+        let conditions = filters
+            .as_ref()
+            .and_then(|filters| filters.get("where"))
+            .and_then(Value::as_object)
+            .cloned()
+            .unwrap_or_default();
+        let filters = filters
+            .map(serde_json::from_value::<Filters>)
+            .transpose()
+            .map_err(|e| ApiError::validation(format!("Invalid filters: {}", e)))?;
+
+        let pool = Database::get_pool().await?;
+
+        if !Table::exists(&pool, &table).await? {
+            return Err(ApiError::new(ErrorCode::UnknownTable, "No such table exists.").into());
+        }
+
+        // rows of tables without a model can't be sent, neither in snapshots
+        // nor in events
+        if !Table::has_model(&table) {
+            return Err(ApiError::validation(format!("{} can't be subscribed to.", table)).into());
+        }
+
+        let mut primary_key = Table::primary_key(&pool, &table).await?;
+        if primary_key.iter().any(|column| mask.hides(column)) {
+            primary_key.clear();
+        }
+
+        let key = id.to_string();
+
+        {
+            let mut registry = self.registry.lock().await;
+
+            if registry.closed {
+                return Err(ApiError::validation("The session is closing.").into());
+            }
+
+            if registry.entries.contains_key(&key) {
+                return Err(
+                    ApiError::validation("A subscription with this id already exists.").into(),
+                );
+            }
+
+            if registry.entries.len() >= config::get().websocket.max_subscriptions {
+                return Err(ApiError::new(
+                    ErrorCode::RateLimit,
+                    "Too many subscriptions on this session.",
+                )
+                .into());
+            }
+
+            // registered before the snapshot is loaded, so no change made in
+            // between is missed
+            registry.entries.insert(
+                key.clone(),
+                Subscription {
+                    id: id.clone(),
+                    codec,
+                    table: table.clone(),
+                    filters: filters.clone(),
+                    conditions,
+                    mask,
+                    key: primary_key,
+                    held: HashSet::new(),
+                    pending: Some(Vec::new()),
+                },
+            );
+
+            if registry.forwarder.is_none() {
+                registry.forwarder = Some(rt::spawn(Self::forward(
+                    self.registry.clone(),
+                    self.sender.clone(),
+                    ChangeBus::subscribe(),
+                )));
+            }
+        }
+
+        let snapshot = Retrieve::retrieve(&pool, &table, filters, CacheMode::Default).await;
+
+        let resync = {
+            let mut registry = self.registry.lock().await;
+
+            let snapshot = match snapshot {
+                Ok(snapshot) => snapshot,
+                Err(e) => {
+                    registry.entries.remove(&key);
+                    return Err(e);
+                }
+            };
+
+            let mut sender = self.sender.clone();
+            let mut resync = false;
+
+            if let Some(subscription) = registry.entries.get_mut(&key) {
+                // a closed session is noticed by the session loop
+                let _ = subscription.send_snapshot(&mut sender, snapshot).await;
+
+                for change in subscription.pending.take().unwrap_or_default() {
+                    match subscription.apply(&mut sender, &change).await {
+                        Ok(needs_snapshot) => resync |= needs_snapshot,
+                        Err(_) => break,
+                    }
+                }
+            }
+
+            resync
+        };
+
+        if resync {
+            let _ = Self::resync(&self.registry, &mut self.sender.clone(), &key).await;
+        }
+
+        Ok(DatabaseResponse::Status {
+            status: "Subscribed.".to_string(),
+        })
+    }
+
+    async fn unsubscribe(&self, subscription: &RequestId) -> Reply {
+        let mut registry = self.registry.lock().await;
+
+        if registry.entries.remove(&subscription.to_string()).is_none() {
+            return Err(ApiError::validation("No such subscription."));
+        }
+
+        // writes only return their rows while someone listens for them
+        if registry.entries.is_empty() {
+            if let Some(forwarder) = registry.forwarder.take() {
+                forwarder.abort();
+            }
+        }
+
+        Ok(DatabaseResponse::Status {
+            status: "Unsubscribed.".to_string(),
+        })
+    }
+
+    /// Drops every subscription once the session ended.
+    pub async fn close(&self) {
+        let mut registry = self.registry.lock().await;
+
+        registry.closed = true;
+        registry.entries.clear();
+
+        if let Some(forwarder) = registry.forwarder.take() {
+            forwarder.abort();
+        }
+    }
+
+    /// Sends the changes published on the bus to the subscriptions they match.
+    async fn forward(
+        registry: Arc<Mutex<Registry>>,
+        mut sender: ResponseSender,
+        mut changes: broadcast::Receiver<Arc<Change>>,
+    ) {
+        loop {
+            let change = match changes.recv().await {
+                Ok(change) => change,
+                // we fell behind and missed some changes
+                Err(RecvError::Lagged(_)) => Arc::new(Change::All),
+                Err(RecvError::Closed) => return,
+            };
+
+            let mut resyncs = Vec::new();
+
+            {
+                let mut registry = registry.lock().await;
+
+                for (key, subscription) in registry.entries.iter_mut() {
+                    if let Some(pending) = &mut subscription.pending {
+                        pending.push(change.clone());
+                        continue;
+                    }
+
+                    match subscription.apply(&mut sender, &change).await {
+                        Ok(true) => resyncs.push(key.clone()),
+                        Ok(false) => {}
+                        Err(_) => return,
+                    }
+                }
+            }
+
+            for key in resyncs {
+                if Self::resync(&registry, &mut sender, &key).await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Sends the subscription `key` a fresh snapshot, for changes we don't
+    /// know the rows of. The registry is not locked while it is loaded.
+    async fn resync(
+        registry: &Mutex<Registry>,
+        sender: &mut ResponseSender,
+        key: &str,
+    ) -> Result<(), Closed> {
+        let Some((table, filters)) = registry
+            .lock()
+            .await
+            .entries
+            .get(key)
+            .map(|subscription| (subscription.table.clone(), subscription.filters.clone()))
+        else {
+            return Ok(());
+        };
+
+        let snapshot = match Database::get_pool().await {
+            Ok(pool) => Retrieve::retrieve(&pool, &table, filters, CacheMode::Default).await,
+            Err(e) => Err(e),
+        };
+
+        let mut registry = registry.lock().await;
+
+        // unsubscribed while the snapshot was loaded
+        let Some(subscription) = registry.entries.get_mut(key) else {
+            return Ok(());
+        };
+
+        match snapshot {
+            Ok(snapshot) => subscription.send_snapshot(sender, snapshot).await,
+            Err(e) => {
+                let event = json!({ "subscription": subscription.id, "error": ApiError::from(e) });
+                sender.send_value(subscription.codec, &event).await
+            }
+        }
+    }
+}
+
+impl Subscription {
+    /// Sends the events `change` causes for this subscription. Returns whether
+    /// the subscription needs a fresh snapshot instead, for changes we don't
+    /// know the rows of.
+    async fn apply(
+        &mut self,
+        sender: &mut ResponseSender,
+        change: &Change,
+    ) -> Result<bool, Closed> {
+        let (op, rows) = match change {
+            Change::Rows { table, op, rows } if *table == self.table => (*op, rows),
+            Change::Table { table } if *table == self.table => return Ok(true),
+            Change::All => return Ok(true),
+            _ => return Ok(false),
+        };
+
+        let mut matching = Vec::new();
+        let mut removed = Vec::new();
+
+        for row in rows {
+            let key = self.key_of(row);
+
+            if self.matches(row) {
+                if let Some(key) = key {
+                    match op {
+                        ChangeOp::Delete => self.held.remove(&key),
+                        _ => self.held.insert(key),
+                    };
+                }
+
+                matching.push(row.clone());
+                continue;
+            }
+
+            // a write can move a row the client holds out of the filter
+            if matches!(op, ChangeOp::Update | ChangeOp::Upsert) {
+                match key {
+                    Some(key) => {
+                        if self.held.remove(&key) {
+                            removed.push(self.key_columns(row));
+                        }
+                    }
+                    // without a key we can't tell whether the client holds it
+                    None => return Ok(true),
+                }
+            }
+        }
+
+        if !removed.is_empty() {
+            let event = json!({ "subscription": self.id, "event": "remove", "rows": removed });
+            sender.send_value(self.codec, &event).await?;
+        }
+
+        self.mask.apply(&mut matching);
+
+        if !matching.is_empty() {
+            let event = json!({ "subscription": self.id, "event": op, "rows": matching });
+            sender.send_value(self.codec, &event).await?;
+        }
+
+        Ok(false)
+    }
+
+    async fn send_snapshot(
+        &mut self,
+        sender: &mut ResponseSender,
+        mut rows: Vec<Value>,
+    ) -> Result<(), Closed> {
+        self.held = rows.iter().filter_map(|row| self.key_of(row)).collect();
+        self.mask.apply(&mut rows);
+
+        let event = json!({ "subscription": self.id, "event": "snapshot", "rows": rows });
+        sender.send_value(self.codec, &event).await
+    }
+
+    /// The primary key of `row` as json, `None` if the table has none.
+    fn key_of(&self, row: &Value) -> Option<String> {
+        if self.key.is_empty() {
+            return None;
+        }
+
+        let key: Vec<&Value> = self
+            .key
+            .iter()
+            .map(|column| row.get(column).unwrap_or(&Value::Null))
+            .collect();
+
+        serde_json::to_string(&key).ok()
+    }
+
+    /// Only the primary key columns of `row`.
+    fn key_columns(&self, row: &Value) -> Value {
+        self.key
+            .iter()
+            .map(|column| {
+                (
+                    column.clone(),
+                    row.get(column).cloned().unwrap_or(Value::Null),
+                )
+            })
+            .collect::<Map<String, Value>>()
+            .into()
+    }
+
+    fn matches(&self, row: &Value) -> bool {
+        self.conditions
+            .iter()
+            .all(|(column, expected)| match (row.get(column), expected) {
+                // 1 and 1.0 are the same value to the database
+                (Some(Value::Number(a)), Value::Number(b)) => a.as_f64() == b.as_f64(),
+                (Some(actual), expected) => actual == expected,
+                (None, _) => false,
+            })
+    }
+}
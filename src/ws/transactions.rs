@@ -0,0 +1,230 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use acid4sigmas_models::models::db::DatabaseResponse;
+use actix_web::rt;
+use serde::Deserialize;
+use serde_json::Value;
+use tokio::sync::Mutex;
+use tokio::time::{sleep, Duration};
+
+use crate::config;
+use crate::db::transaction::{SessionTransaction, SharedTransaction};
+use crate::db::Database;
+use crate::error::{ApiError, ErrorCode};
+use crate::protocol::{Reply, RequestId};
+
+/// Transaction actions, handled by the session itself instead of the
+/// `DatabaseHandler`.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "action")]
+pub enum TransactionRequest {
+    Begin,
+    Commit { transaction: RequestId },
+    Rollback { transaction: RequestId },
+}
+
+impl TransactionRequest {
+    /// Whether the raw request is one of the transaction actions.
+    pub fn is_transaction(value: &Value) -> bool {
+        matches!(
+            value.get("action").and_then(Value::as_str),
+            Some("Begin" | "Commit" | "Rollback")
+        )
+    }
+}
+
+#[derive(Default)]
+struct Registry {
+    entries: HashMap<String, SharedTransaction>,
+    closed: bool,
+}
+
+/// Transactions a single session opened with `Begin`.
+///
+/// Each holds a database connection until it is committed or rolled back, it
+/// is rolled back when it stays unused for `websocket.transaction_timeout_secs`
+/// or the session ends. Cloning it is cheap, all clones share the same
+/// transactions.
+#[derive(Clone, Default)]
+pub struct Transactions {
+    registry: Arc<Mutex<Registry>>,
+}
+
+impl Transactions {
+    pub async fn handle(&self, id: Option<&RequestId>, request: TransactionRequest) -> Reply {
+        match request {
+            TransactionRequest::Begin => {
+                let id = id.ok_or_else(|| {
+                    ApiError::validation("Begin needs an id to refer to the transaction with.")
+                })?;
+
+                self.begin(id).await
+            }
+            TransactionRequest::Commit { transaction } => self.commit(&transaction).await,
+            TransactionRequest::Rollback { transaction } => self.rollback(&transaction).await,
+        }
+    }
+
+    /// Returns the open transaction a request refers to.
+    pub async fn get(&self, transaction: &RequestId) -> Result<SharedTransaction, ApiError> {
+        let registry = self.registry.lock().await;
+
+        registry
+            .entries
+            .get(&transaction.to_string())
+            .cloned()
+            .ok_or_else(|| ApiError::validation("No such transaction."))
+    }
+
+    async fn begin(&self, id: &RequestId) -> Reply {
+        let key = id.to_string();
+        self.check_begin(&key).await?;
+
+        // without holding the registry, the other requests of the session keep
+        // running while the connection is made
+        let transaction = async {
+            let pool = Database::get_pool().await?;
+            SessionTransaction::begin(&pool).await
+        }
+        .await
+        .map_err(ApiError::from)?;
+
+        let mut registry = self.registry.lock().await;
+        // the session may have closed or used the id in the meantime, the
+        // transaction is rolled back when it is dropped
+        Self::check_entry(&registry, &key)?;
+
+        let transaction: SharedTransaction = Arc::new(Mutex::new(Some(transaction)));
+        registry.entries.insert(key.clone(), transaction.clone());
+        drop(registry);
+
+        rt::spawn(self.clone().expire(key, transaction));
+
+        Ok(DatabaseResponse::Status {
+            status: "Transaction started.".to_string(),
+        })
+    }
+
+    async fn check_begin(&self, key: &str) -> Result<(), ApiError> {
+        let registry = self.registry.lock().await;
+        Self::check_entry(&registry, key)
+    }
+
+    /// Whether a new transaction may be registered under `key`.
+    fn check_entry(registry: &Registry, key: &str) -> Result<(), ApiError> {
+        if registry.closed {
+            return Err(ApiError::validation("The session is closing."));
+        }
+
+        if registry.entries.contains_key(key) {
+            return Err(ApiError::validation(
+                "A transaction with this id already exists.",
+            ));
+        }
+
+        if registry.entries.len() >= config::get().websocket.max_transactions {
+            return Err(ApiError::new(
+                ErrorCode::RateLimit,
+                "Too many open transactions on this session.",
+            ));
+        }
+
+        Ok(())
+    }
+
+    async fn commit(&self, id: &RequestId) -> Reply {
+        let transaction = self.take(id).await?;
+
+        transaction.commit().await.map_err(ApiError::from)?;
+
+        Ok(DatabaseResponse::Status {
+            status: "Transaction committed.".to_string(),
+        })
+    }
+
+    async fn rollback(&self, id: &RequestId) -> Reply {
+        let transaction = self.take(id).await?;
+
+        transaction.rollback().await.map_err(ApiError::from)?;
+
+        Ok(DatabaseResponse::Status {
+            status: "Transaction rolled back.".to_string(),
+        })
+    }
+
+    /// Removes a transaction, waiting for the request currently using it.
+    async fn take(&self, id: &RequestId) -> Result<SessionTransaction, ApiError> {
+        let transaction = self
+            .registry
+            .lock()
+            .await
+            .entries
+            .remove(&id.to_string())
+            .ok_or_else(|| ApiError::validation("No such transaction."))?;
+
+        let transaction = transaction.lock().await.take();
+        transaction.ok_or_else(|| ApiError::validation("No such transaction."))
+    }
+
+    /// Rolls back every open transaction once the session ended.
+    pub async fn close(&self) {
+        let transactions: Vec<SharedTransaction> = {
+            let mut registry = self.registry.lock().await;
+            registry.closed = true;
+            registry.entries.drain().map(|(_, t)| t).collect()
+        };
+
+        for transaction in transactions {
+            let transaction = transaction.lock().await.take();
+
+            if let Some(transaction) = transaction {
+                if let Err(e) = transaction.rollback().await {
+                    eprintln!("error: failed to roll back transaction: {}", e);
+                }
+            }
+        }
+    }
+
+    /// Rolls the transaction back once it stayed unused for too long.
+    async fn expire(self, key: String, transaction: SharedTransaction) {
+        let timeout = Duration::from_secs(config::get().websocket.transaction_timeout_secs);
+        let mut wait = timeout;
+
+        loop {
+            sleep(wait).await;
+
+            let mut guard = transaction.lock().await;
+
+            let idle = match guard.as_ref() {
+                Some(open) => open.idle(),
+                // committed or rolled back already
+                None => return,
+            };
+
+            if idle < timeout {
+                wait = timeout - idle;
+                continue;
+            }
+
+            if let Some(open) = guard.take() {
+                if let Err(e) = open.rollback().await {
+                    eprintln!("error: failed to roll back transaction: {}", e);
+                }
+            }
+            drop(guard);
+
+            // unless the id was reused for a new transaction in the meantime
+            let mut registry = self.registry.lock().await;
+            if registry
+                .entries
+                .get(&key)
+                .is_some_and(|entry| Arc::ptr_eq(entry, &transaction))
+            {
+                registry.entries.remove(&key);
+            }
+
+            return;
+        }
+    }
+}